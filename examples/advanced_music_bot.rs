@@ -6,16 +6,14 @@ use log::error;
 use std::time::Duration;
 
 // Import the `Context` to handle commands.
-use charcoal_client::serenity::{CharcoalKey, SerenityInit};
+use charcoal_client::serenity::{get_charcoal, get_player_for, SerenityInit};
 use serenity::client::Context;
 
 use charcoal_client::actions::channel_manager::ChannelManager;
 use charcoal_client::actions::player::Player;
 use charcoal_client::actions::track_manager::TrackManager;
-use charcoal_client::{
-    get_handler_from_serenity, get_handler_from_serenity_mutable, CharcoalConfig, PlayerObject,
-    SASLConfig,
-};
+use charcoal_client::now_playing::NowPlaying;
+use charcoal_client::CharcoalConfig;
 
 // IMPORTANT NOTE:
 // This example uses unwrap()s on the Results<> from charcoal
@@ -95,20 +93,15 @@ async fn main() {
         .event_handler(Handler)
         .framework(framework)
         // Add a Kafka URL here to connect to the broker
+        // Reads the brokers, topic and SASL credentials from CHARCOAL_BROKERS, CHARCOAL_KAFKA_TOPIC,
+        // CHARCOAL_KAFKA_USERNAME and CHARCOAL_KAFKA_PASSWORD
         .register_charcoal(
-            env::var("KAFKA_BROKER").expect("Expected KAFKA_BROKER env variable"),
-            CharcoalConfig {
-                sasl: Some(SASLConfig {
-                    kafka_username: env::var("KAFKA_USER")
-                        .expect("Expected KAFKA_BROKER env variable"),
-                    kafka_password: env::var("KAFKA_PASS")
-                        .expect("Expected KAFKA_BROKER env variable"),
-                }),
-                ssl: None,
-                kafka_topic: "communication".to_string(),
-            },
+            String::new(),
+            CharcoalConfig::from_env().expect("Invalid Charcoal config"),
         )
         .await
+        .expect("Failed to initialize Charcoal")
+        .await
         .expect("Err creating client");
 
     tokio::spawn(async move {
@@ -118,23 +111,24 @@ async fn main() {
             .map_err(|why| println!("Client ended: {:?}", why));
     });
 
-    tokio::signal::ctrl_c().await;
+    tokio::signal::ctrl_c()
+        .await
+        .expect("Failed to listen for Ctrl-C");
     println!("Received Ctrl-C, shutting down.");
 }
 
 #[command]
 #[only_in(guilds)]
 async fn pause(ctx: &Context, msg: &Message) -> CommandResult {
-    // Get the PlayerObject using a helper macro
-    let mut handler: Option<&PlayerObject> = None;
-    get_handler_from_serenity!(ctx, msg, handler);
+    // Get the PlayerObject of the guild the message was sent in
+    let handler = get_player_for(ctx, msg).await;
 
     match handler {
-        Some(handler) => {
+        Ok(handler) => {
             handler.pause_playback().await.unwrap();
         }
-        None => {
-            error!("Failed to get manager!");
+        Err(e) => {
+            error!("Failed to get player: {}", e);
         }
     }
 
@@ -144,31 +138,18 @@ async fn pause(ctx: &Context, msg: &Message) -> CommandResult {
 #[command]
 #[only_in(guilds)]
 async fn resume(ctx: &Context, msg: &Message) -> CommandResult {
-    // Get the PlayerObject using a helper macro
-    let mut handler: Option<&PlayerObject> = None;
-    get_handler_from_serenity!(ctx, msg, handler);
-
-    // If you don't want to use the macro you can also get the PlayerObject like this
-    // As it is pretty much equivalent to the above macro:
-
-    // // Get the serenity typemap
-    // let r = ctx.data.read().await;
-    // // Get the GuildID
-    // let guild = msg.guild(&ctx.cache).unwrap();
-    // let guild_id = guild.id;
-    // // Get the charcoal manager from the serenity typemap
-    // let manager = r.get::<CharcoalKey>();
-    // let mut mx = manager.unwrap().lock().await;
-    // // Get the PlayerObject
-    // let mut players = mx.players.write().await;
-    // let handler =  players.get_mut(&guild_id.to_string());
+    // Get the PlayerObject of the guild the message was sent in
+    let handler = get_player_for(ctx, msg).await;
+
+    // If you already have the GuildId, for example from a slash command, you can also use:
+    // let handler = get_player(ctx, guild_id).await;
 
     match handler {
-        Some(handler) => {
+        Ok(handler) => {
             handler.resume_playback().await.unwrap();
         }
-        None => {
-            error!("Failed to get manager!");
+        Err(e) => {
+            error!("Failed to get player: {}", e);
         }
     }
 
@@ -197,62 +178,34 @@ async fn join(ctx: &Context, msg: &Message) -> CommandResult {
     };
 
     // Get the manager from the serenity typemap
-    let r = ctx.data.write().await;
-    let manager = r.get::<CharcoalKey>();
-    let mx = manager.unwrap().lock().await;
+    let manager = match get_charcoal(ctx).await {
+        Ok(manager) => manager,
+        Err(e) => {
+            error!("Failed to get manager: {}", e);
+            return Ok(());
+        }
+    };
 
-    // Check if we have already created the player by checking if the player's GuildID exists in the Players HashMap
-    // Stored inside of the Charcoal Instance.
+    // Check if we have already created the player for this guild
     // If we have already created the player just join the channel
-    if mx.players.read().await.contains_key(&guild_id.to_string()) {
+    if let Some(handler) = manager.player(guild_id) {
         println!("Using pre-existing player");
-        // Get a write lock on the players HashMap
-        let mut players = mx.players.write().await;
-        // Get a mutable reference to said player
-        let handler = players.get_mut(&guild_id.to_string()).expect(
-            "This should never happen because we checked the key exists in the if check above",
-        );
         // Join the channel
         handler
-            .join_channel(connect_to.to_string(), false)
+            .join_channel(connect_to.into(), false)
             .await
             .unwrap(); // We use false here so Charcoal does not create a pre-existing job
     } else {
         println!("Creating new player");
         // If we have not created the player create it and then join the channel
-        let handler = PlayerObject::new(guild_id.to_string(), mx.tx.clone()).await;
+        let handler = manager.get_or_create_player(guild_id);
         println!("Created new handler");
-        // Make sure creating the PlayerObject worked
-        match handler {
-            Ok(mut handler) => {
-                // Register an error callback so errors from the hearth server can be reported back to us
-                handler.register_event_handler(CustomEventHandler {}).await;
-                // Join the channel
-                println!("Registered error callback");
-                handler
-                    .join_channel(connect_to.to_string(), true)
-                    .await
-                    .unwrap(); // We use true here to tell Charcoal to create the Job
-                println!("Joined channel");
-                // Insert the newly created PlayerObject into the HashMap so we can use it later
-                mx.players
-                    .write()
-                    .await
-                    .insert(guild_id.to_string(), handler);
-                println!("Inserted new player");
-            }
-            Err(e) => {
-                // If creating the job failed send an error message
-                check_msg(
-                    msg.channel_id
-                        .say(
-                            &ctx.http,
-                            format!("Failed to register PlayerObject with error: {}", e),
-                        )
-                        .await,
-                );
-            }
-        }
+        // Register an error callback so errors from the hearth server can be reported back to us
+        handler.register_event_handler(CustomEventHandler {}).await;
+        println!("Registered error callback");
+        // Join the channel
+        handler.join_channel(connect_to.into(), true).await.unwrap(); // We use true here to tell Charcoal to create the Job
+        println!("Joined channel");
     }
 
     Ok(())
@@ -261,18 +214,22 @@ async fn join(ctx: &Context, msg: &Message) -> CommandResult {
 #[command]
 #[only_in(guilds)]
 async fn metadata(ctx: &Context, msg: &Message) -> CommandResult {
-    // Get the PlayerObject using a helper macro
-    let mut handler: Option<&mut PlayerObject> = None;
-    // This get's a mutable PlayerObject instead of a constant one
-    // Be careful where you use this as getting the playerobject as mutable locks the internal RwLock Mutex
-    get_handler_from_serenity_mutable!(ctx, msg, handler);
+    // Get the PlayerObject of the guild the message was sent in
+    let handler = get_player_for(ctx, msg).await;
 
     match handler {
-        Some(handler) => {
+        Ok(handler) => {
             handler.get_metadata().await.unwrap();
+            // Show what is playing using Charcoal's local view of the player
+            let now_playing = NowPlaying::from_player(&handler).await;
+            check_msg(
+                msg.channel_id
+                    .send_message(&ctx.http, |m| m.set_embed(now_playing.to_embed()))
+                    .await,
+            );
         }
-        None => {
-            error!("Failed to get manager!");
+        Err(e) => {
+            error!("Failed to get player: {}", e);
         }
     }
 
@@ -282,17 +239,16 @@ async fn metadata(ctx: &Context, msg: &Message) -> CommandResult {
 #[command]
 #[only_in(guilds)]
 async fn loopforever(ctx: &Context, msg: &Message) -> CommandResult {
-    // Get the PlayerObject using a helper macro
-    let mut handler: Option<&PlayerObject> = None;
-    get_handler_from_serenity!(ctx, msg, handler);
+    // Get the PlayerObject of the guild the message was sent in
+    let handler = get_player_for(ctx, msg).await;
 
     match handler {
-        Some(handler) => {
+        Ok(handler) => {
             let _meta = handler.loop_indefinitely().await;
             check_msg(msg.channel_id.say(&ctx.http, "Looping forever!").await);
         }
-        None => {
-            error!("Failed to get manager!");
+        Err(e) => {
+            error!("Failed to get player: {}", e);
         }
     }
 
@@ -302,17 +258,24 @@ async fn loopforever(ctx: &Context, msg: &Message) -> CommandResult {
 #[command]
 #[only_in(guilds)]
 async fn leave(ctx: &Context, msg: &Message) -> CommandResult {
-    // Get the PlayerObject using a helper macro
-    let mut handler: Option<&PlayerObject> = None;
-    get_handler_from_serenity!(ctx, msg, handler);
+    let guild_id = msg.guild_id.unwrap();
 
-    match handler {
-        Some(handler) => {
-            handler.exit_channel().await;
-        }
-        None => {
-            error!("Failed to get manager!");
+    // Get the manager from the serenity typemap
+    let manager = match get_charcoal(ctx).await {
+        Ok(manager) => manager,
+        Err(e) => {
+            error!("Failed to get manager: {}", e);
+            return Ok(());
         }
+    };
+
+    // Leave the channel and remove the player so its resources are cleaned up
+    let removed = manager.remove_player(guild_id, true).await;
+    if removed.player.is_none() {
+        error!("Not in a voice channel");
+    }
+    if let Some(e) = removed.leave_error {
+        error!("Failed to leave voice channel: {}", e);
     }
 
     Ok(())
@@ -351,17 +314,16 @@ async fn play(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
         return Ok(());
     }
 
-    // Get the PlayerObject using a helper macro
-    let mut handler: Option<&mut PlayerObject> = None;
-    get_handler_from_serenity_mutable!(ctx, msg, handler);
+    // Get the PlayerObject of the guild the message was sent in
+    let handler = get_player_for(ctx, msg).await;
 
     match handler {
-        Some(handler) => {
+        Ok(handler) => {
             handler.play_from_http(url).await.unwrap();
             check_msg(msg.channel_id.say(&ctx.http, "Playing song").await);
         }
-        None => {
-            error!("Failed to get manager!");
+        Err(e) => {
+            error!("Failed to get player: {}", e);
         }
     }
 
@@ -394,12 +356,11 @@ async fn youtube(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult
         return Ok(());
     }
 
-    // Get the PlayerObject using a helper macro
-    let mut handler: Option<&mut PlayerObject> = None;
-    get_handler_from_serenity_mutable!(ctx, msg, handler);
+    // Get the PlayerObject of the guild the message was sent in
+    let handler = get_player_for(ctx, msg).await;
 
     match handler {
-        Some(handler) => {
+        Ok(handler) => {
             handler.play_from_youtube(url).await.unwrap();
             check_msg(
                 msg.channel_id
@@ -407,8 +368,8 @@ async fn youtube(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult
                     .await,
             );
         }
-        None => {
-            error!("Failed to get manager!");
+        Err(e) => {
+            error!("Failed to get player: {}", e);
         }
     }
 
@@ -433,18 +394,17 @@ async fn volume(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
 
     // Make sure that volume is between 0 and 1. As for performance reasons the Hearth server does not have soft-clipping enabled
     // So any values above 1 may clip
-    if volume >= 0.0 && volume <= 1.0 {
-        // Get the PlayerObject using a helper macro
-        let mut handler: Option<&PlayerObject> = None;
-        get_handler_from_serenity!(ctx, msg, handler);
+    if (0.0..=1.0).contains(&volume) {
+        // Get the PlayerObject of the guild the message was sent in
+        let handler = get_player_for(ctx, msg).await;
 
         match handler {
-            Some(handler) => {
+            Ok(handler) => {
                 handler.set_playback_volume(volume).await.unwrap();
                 check_msg(msg.channel_id.say(&ctx.http, "Set volume").await);
             }
-            None => {
-                error!("Failed to get manager!");
+            Err(e) => {
+                error!("Failed to get player: {}", e);
             }
         }
     } else {
@@ -461,17 +421,16 @@ async fn volume(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
 #[command]
 #[only_in(guilds)]
 async fn stoploop(ctx: &Context, msg: &Message) -> CommandResult {
-    // Get the PlayerObject using a helper macro
-    let mut handler: Option<&PlayerObject> = None;
-    get_handler_from_serenity!(ctx, msg, handler);
+    // Get the PlayerObject of the guild the message was sent in
+    let handler = get_player_for(ctx, msg).await;
 
     match handler {
-        Some(handler) => {
+        Ok(handler) => {
             handler.force_stop_loop().await.unwrap();
             check_msg(msg.channel_id.say(&ctx.http, "Canceled Loop").await);
         }
-        None => {
-            error!("Failed to get manager!");
+        Err(e) => {
+            error!("Failed to get player: {}", e);
         }
     }
 
@@ -494,12 +453,11 @@ async fn looptimes(ctx: &Context, msg: &Message, mut args: Args) -> CommandResul
         }
     };
 
-    // Get the PlayerObject using a helper macro
-    let mut handler: Option<&PlayerObject> = None;
-    get_handler_from_serenity!(ctx, msg, handler);
+    // Get the PlayerObject of the guild the message was sent in
+    let handler = get_player_for(ctx, msg).await;
 
     match handler {
-        Some(handler) => {
+        Ok(handler) => {
             handler.loop_x_times(times).await.unwrap();
             check_msg(
                 msg.channel_id
@@ -507,8 +465,8 @@ async fn looptimes(ctx: &Context, msg: &Message, mut args: Args) -> CommandResul
                     .await,
             );
         }
-        None => {
-            error!("Failed to get manager!");
+        Err(e) => {
+            error!("Failed to get player: {}", e);
         }
     }
 
@@ -531,20 +489,19 @@ async fn position(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult
         }
     };
 
-    // Get the PlayerObject using a helper macro
-    let mut handler: Option<&PlayerObject> = None;
-    get_handler_from_serenity!(ctx, msg, handler);
+    // Get the PlayerObject of the guild the message was sent in
+    let handler = get_player_for(ctx, msg).await;
 
     match handler {
-        Some(handler) => {
+        Ok(handler) => {
             handler
                 .seek_to_position(Duration::from_secs(position))
                 .await
                 .unwrap();
             check_msg(msg.channel_id.say(&ctx.http, "Seeking...").await);
         }
-        None => {
-            error!("Failed to get manager!");
+        Err(e) => {
+            error!("Failed to get player: {}", e);
         }
     }
 
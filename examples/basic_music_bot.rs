@@ -3,7 +3,7 @@ use std::env;
 use log::error;
 
 // Import the `Context` to handle commands.
-use charcoal_client::serenity::{CharcoalKey, SerenityInit};
+use charcoal_client::serenity::{get_charcoal, get_player_for, SerenityInit};
 use serenity::client::Context;
 
 // IMPORTANT NOTE:
@@ -29,10 +29,7 @@ use serenity::{
 use charcoal_client::actions::channel_manager::ChannelManager;
 use charcoal_client::actions::player::Player;
 use charcoal_client::actions::track_manager::TrackManager;
-use charcoal_client::{
-    get_handler_from_serenity, get_handler_from_serenity_mutable, CharcoalConfig, PlayerObject,
-    SSLConfig,
-};
+use charcoal_client::CharcoalConfig;
 
 struct Handler;
 
@@ -63,20 +60,19 @@ async fn main() {
     let mut client = Client::builder(&token, intents)
         .event_handler(Handler)
         .framework(framework)
-        // Add a Kafka URL here to connect to the broker
+        // The Kafka broker is part of the config so no extra brokers are passed here
         .register_charcoal(
-            env::var("KAFKA_BROKER").expect("Expected KAFKA_BROKER env variable"),
-            CharcoalConfig {
-                ssl: Some(SSLConfig {
-                    ssl_ca: "ca.pem".to_string(),
-                    ssl_cert: "service.cert".to_string(),
-                    ssl_key: "service.key".to_string(),
-                }),
-                sasl: None,
-                kafka_topic: "communication".to_string(),
-            },
+            String::new(),
+            CharcoalConfig::builder()
+                .ssl("ca.pem", "service.cert", "service.key")
+                .kafka_topic("communication")
+                .broker(env::var("KAFKA_BROKER").expect("Expected KAFKA_BROKER env variable"))
+                .build()
+                .expect("Invalid Charcoal config"),
         )
         .await
+        .expect("Failed to initialize Charcoal")
+        .await
         .expect("Err creating client");
 
     tokio::spawn(async move {
@@ -86,23 +82,24 @@ async fn main() {
             .map_err(|why| println!("Client ended: {:?}", why));
     });
 
-    tokio::signal::ctrl_c().await;
+    tokio::signal::ctrl_c()
+        .await
+        .expect("Failed to listen for Ctrl-C");
     println!("Received Ctrl-C, shutting down.");
 }
 
 #[command]
 #[only_in(guilds)]
 async fn pause(ctx: &Context, msg: &Message) -> CommandResult {
-    // Get the PlayerObject using a helper macro
-    let mut handler: Option<&PlayerObject> = None;
-    get_handler_from_serenity!(ctx, msg, handler);
+    // Get the PlayerObject of the guild the message was sent in
+    let handler = get_player_for(ctx, msg).await;
 
     match handler {
-        Some(handler) => {
+        Ok(handler) => {
             handler.pause_playback().await.unwrap();
         }
-        None => {
-            error!("Failed to get manager!");
+        Err(e) => {
+            error!("Failed to get player: {}", e);
         }
     }
 
@@ -112,31 +109,18 @@ async fn pause(ctx: &Context, msg: &Message) -> CommandResult {
 #[command]
 #[only_in(guilds)]
 async fn resume(ctx: &Context, msg: &Message) -> CommandResult {
-    // Get the PlayerObject using a helper macro
-    let mut handler: Option<&PlayerObject> = None;
-    get_handler_from_serenity!(ctx, msg, handler);
-
-    // If you don't want to use the macro you can also get the PlayerObject like this
-    // As it is pretty much equivalent to the above macro:
-
-    // // Get the serenity typemap
-    // let r = ctx.data.read().await;
-    // // Get the GuildID
-    // let guild = msg.guild(&ctx.cache).unwrap();
-    // let guild_id = guild.id;
-    // // Get the charcoal manager from the serenity typemap
-    // let manager = r.get::<CharcoalKey>();
-    // let mut mx = manager.unwrap().lock().await;
-    // // Get the PlayerObject
-    // let mut players = mx.players.write().await;
-    // let handler =  players.get_mut(&guild_id.to_string());
+    // Get the PlayerObject of the guild the message was sent in
+    let handler = get_player_for(ctx, msg).await;
+
+    // If you already have the GuildId, for example from a slash command, you can also use:
+    // let handler = get_player(ctx, guild_id).await;
 
     match handler {
-        Some(handler) => {
+        Ok(handler) => {
             handler.resume_playback().await.unwrap();
         }
-        None => {
-            error!("Failed to get manager!");
+        Err(e) => {
+            error!("Failed to get player: {}", e);
         }
     }
 
@@ -164,48 +148,30 @@ async fn join(ctx: &Context, msg: &Message) -> CommandResult {
     };
 
     // Get the manager from the serenity typemap
-    let r = ctx.data.write().await;
-    let manager = r.get::<CharcoalKey>();
-    let mx = manager.unwrap().lock().await;
+    let manager = match get_charcoal(ctx).await {
+        Ok(manager) => manager,
+        Err(e) => {
+            error!("Failed to get manager: {}", e);
+            return Ok(());
+        }
+    };
 
-    // Check if we have already created the player by checking if the player's GuildID exists in the Players HashMap
-    // Stored inside of the Charcoal Instance.
+    // Check if we have already created the player for this guild
     // If we have already created the player just join the channel
-    if mx.players.read().await.contains_key(&guild_id.to_string()) {
-        // Get a write lock on the players HashMap
-        let mut players = mx.players.write().await;
-        // Get a mutable reference to said player
-        let handler = players.get_mut(&guild_id.to_string()).expect(
-            "This should never happen because we checked the key exists in the if check above",
-        );
+    if let Some(handler) = manager.player(guild_id) {
         // Join the channel
-        handler.join_channel(connect_to.to_string(),false).await;
+        handler
+            .join_channel(connect_to.into(), false)
+            .await
+            .unwrap(); // We use false here so Charcoal does not create a pre-existing job
     } else {
         // If we have not created the player create it and then join the channel
-        let handler = PlayerObject::new(guild_id.to_string(), mx.tx.clone()).await;
-        // Make sure creating the PlayerObject worked
-        match handler {
-            Ok(mut handler) => {
-                // Join the channel
-                handler.join_channel(connect_to.to_string(),true).await;
-                // Insert the newly created PlayerObject into the HashMap so we can use it later
-                mx.players
-                    .write()
-                    .await
-                    .insert(guild_id.to_string(), handler);
-            }
-            Err(e) => {
-                // If creating the job failed send an error message
-                check_msg(
-                    msg.channel_id
-                        .say(
-                            &ctx.http,
-                            format!("Failed to register PlayerObject with error: {}", e),
-                        )
-                        .await,
-                );
-            }
-        }
+        let handler = manager.get_or_create_player(guild_id);
+        // Join the channel
+        handler
+            .join_channel(connect_to.into(), true)
+            .await
+            .unwrap(); // We use true here to tell Charcoal to create the Job
     }
 
     Ok(())
@@ -214,19 +180,15 @@ async fn join(ctx: &Context, msg: &Message) -> CommandResult {
 #[command]
 #[only_in(guilds)]
 async fn metadata(ctx: &Context, msg: &Message) -> CommandResult {
-    // Get the PlayerObject using a helper macro
-    let mut handler: Option<&mut PlayerObject> = None;
-    // This get's a mutable PlayerObject instead of a constant one
-    // Be careful where you use this as getting the playerobject as mutable locks the internal RwLock Mutex
-    get_handler_from_serenity_mutable!(ctx, msg, handler);
+    // Get the PlayerObject of the guild the message was sent in
+    let handler = get_player_for(ctx, msg).await;
 
     match handler {
-        Some(handler) => {
-            let meta = handler.get_metadata().await.unwrap();
-            println!("{:?}", meta);
+        Ok(handler) => {
+            handler.get_metadata().await.unwrap();
         }
-        None => {
-            error!("Failed to get manager!");
+        Err(e) => {
+            error!("Failed to get player: {}", e);
         }
     }
 
@@ -236,17 +198,16 @@ async fn metadata(ctx: &Context, msg: &Message) -> CommandResult {
 #[command]
 #[only_in(guilds)]
 async fn loopforever(ctx: &Context, msg: &Message) -> CommandResult {
-    // Get the PlayerObject using a helper macro
-    let mut handler: Option<&PlayerObject> = None;
-    get_handler_from_serenity!(ctx, msg, handler);
+    // Get the PlayerObject of the guild the message was sent in
+    let handler = get_player_for(ctx, msg).await;
 
     match handler {
-        Some(handler) => {
+        Ok(handler) => {
             let _meta = handler.loop_indefinitely().await;
             check_msg(msg.channel_id.say(&ctx.http, "Looping forever!").await);
         }
-        None => {
-            error!("Failed to get manager!");
+        Err(e) => {
+            error!("Failed to get player: {}", e);
         }
     }
 
@@ -256,17 +217,24 @@ async fn loopforever(ctx: &Context, msg: &Message) -> CommandResult {
 #[command]
 #[only_in(guilds)]
 async fn leave(ctx: &Context, msg: &Message) -> CommandResult {
-    // Get the PlayerObject using a helper macro
-    let mut handler: Option<&PlayerObject> = None;
-    get_handler_from_serenity!(ctx, msg, handler);
+    let guild_id = msg.guild_id.unwrap();
 
-    match handler {
-        Some(handler) => {
-            handler.exit_channel().await;
-        }
-        None => {
-            error!("Failed to get manager!");
+    // Get the manager from the serenity typemap
+    let manager = match get_charcoal(ctx).await {
+        Ok(manager) => manager,
+        Err(e) => {
+            error!("Failed to get manager: {}", e);
+            return Ok(());
         }
+    };
+
+    // Leave the channel and remove the player so its resources are cleaned up
+    let removed = manager.remove_player(guild_id, true).await;
+    if removed.player.is_none() {
+        error!("Not in a voice channel");
+    }
+    if let Some(e) = removed.leave_error {
+        error!("Failed to leave voice channel: {}", e);
     }
 
     Ok(())
@@ -305,17 +273,16 @@ async fn play(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
         return Ok(());
     }
 
-    // Get the PlayerObject using a helper macro
-    let mut handler: Option<&mut PlayerObject> = None;
-    get_handler_from_serenity_mutable!(ctx, msg, handler);
+    // Get the PlayerObject of the guild the message was sent in
+    let handler = get_player_for(ctx, msg).await;
 
     match handler {
-        Some(handler) => {
+        Ok(handler) => {
             handler.play_from_http(url).await.unwrap();
             check_msg(msg.channel_id.say(&ctx.http, "Playing song").await);
         }
-        None => {
-            error!("Failed to get manager!");
+        Err(e) => {
+            error!("Failed to get player: {}", e);
         }
     }
 
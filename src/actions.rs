@@ -6,6 +6,12 @@ pub mod channel_manager;
 /// Allows you to start playback using an HttpRequest or from a Youtube URL
 pub mod player;
 
+/// Keeps track of previously played tracks and allows going back to them
+pub mod history;
+
+/// Client-side queue that plays tracks one after another
+pub mod queue;
+
 pub mod standard;
 /// Provides functionality that can be used once you start playing a track such as: looping, pausing, and resuming.
 pub mod track_manager;
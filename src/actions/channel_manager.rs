@@ -1,11 +1,13 @@
 use hearth_interconnect::messages::{JobRequest, Message};
 use hearth_interconnect::worker_communication::{DWCActionType, DirectWorkerCommunication};
 use std::time::Duration;
+use crate::ids::{JobId, RequestId, VoiceChannelId, WorkerId};
 use crate::PlayerObject;
 use async_trait::async_trait;
-use nanoid::nanoid;
 use crate::background::connector::{boilerplate_parse_ipc, BoilerplateParseIPCError};
 use crate::background::processor::IPCData;
+use crate::capabilities::CapabilityError;
+use crate::permissions::PermissionDenied;
 use snafu::prelude::*;
 use tokio::sync::broadcast::error::SendError;
 
@@ -14,21 +16,39 @@ pub enum CreateJobError {
     #[snafu(display("Did not receive job creation confirmation within time-frame"))]
     TimedOutWaitingForJobCreationConfirmation { source: BoilerplateParseIPCError },
     #[snafu(display("Failed to send internal IPC job creation request"))]
-    FailedToSendIPC { source: SendError<IPCData> },
+    FailedToSendIPC {
+        #[snafu(source(from(SendError<IPCData>, Box::new)))]
+        source: Box<SendError<IPCData>>,
+    },
+    #[snafu(display("Player has no active job, join with create_job set to true first"))]
+    NoActiveJob,
+    #[snafu(context(false), display("Worker can't run this action"))]
+    Unsupported { source: CapabilityError },
+    #[snafu(context(false), display("User isn't allowed to run this action"))]
+    Denied { source: PermissionDenied },
 }
 
 #[derive(Debug, Snafu)]
 pub enum ChannelManagerError {
+    #[snafu(display("Player has no active job"))]
+    NoJob,
     #[snafu(display("Failed to send IPC request to Background thread"))]
-    FailedToSendIPCRequest { source: SendError<IPCData> },
+    FailedToSendIPCRequest {
+        #[snafu(source(from(SendError<IPCData>, Box::new)))]
+        source: Box<SendError<IPCData>>,
+    },
+    #[snafu(context(false), display("Worker can't run this action"))]
+    Unsupported { source: CapabilityError },
+    #[snafu(context(false), display("User isn't allowed to run this action"))]
+    Denied { source: PermissionDenied },
 }
 
 /// Provides basic functionality to create a job on the hearth server, join a channel, and exit a channel
 #[async_trait]
 pub trait ChannelManager {
     async fn join_channel(
-        &mut self,
-        voice_channel_id: String,
+        &self,
+        voice_channel_id: VoiceChannelId,
         create_job: bool,
     ) -> Result<(), CreateJobError>;
     async fn exit_channel(&self) -> Result<(), ChannelManagerError>;
@@ -38,110 +58,87 @@ pub trait ChannelManager {
 impl ChannelManager for PlayerObject {
     /// Create job on Hearth server for this PlayerObject
     async fn join_channel(
-        &mut self,
-        voice_channel_id: String,
+        &self,
+        voice_channel_id: VoiceChannelId,
         create_job: bool,
     ) -> Result<(), CreateJobError> {
-        let guild_id = self.guild_id.clone();
-
-        let tx = self.tx.clone();
-        let bg_com = self.bg_com_tx.clone();
-
-        let worker_id = self.worker_id.clone();
-        let job_id = self.job_id.clone();
-
-        if !create_job {
+        if create_job {
+            // Subscribe before sending so the response can't be missed
+            let rx = self.tx.subscribe();
             self.bg_com_tx
                 .send(IPCData::new_from_main(
-                    Message::DirectWorkerCommunication(DirectWorkerCommunication {
-                        job_id: job_id.read().await.clone().unwrap(),
-                        worker_id: worker_id.read().await.clone().unwrap(),
-                        guild_id: self.guild_id.clone(),
-                        voice_channel_id: Some(voice_channel_id.clone()),
-                        play_audio_url: None,
-                        action_type: DWCActionType::JoinChannel,
-                        request_id: Some(nanoid!()),
-                        new_volume: None,
-                        seek_position: None,
-                        loop_times: None,
+                    Message::ExternalQueueJob(JobRequest {
+                        request_id: RequestId::new().into(),
+                        guild_id: self.guild_id.clone().into(),
                     }),
                     self.tx.clone(),
                     self.guild_id.clone(),
                 ))
                 .context(FailedToSendIPCSnafu)?;
 
-            return Ok(());
-        }
-
-        tokio::spawn(async move {
-            bg_com
-                .send(IPCData::new_from_main(
-                    Message::ExternalQueueJob(JobRequest {
-                        request_id: nanoid!(),
-                        guild_id: guild_id.clone(),
-                    }),
-                    tx.clone(),
-                    guild_id.clone(),
-                ))
-                .unwrap();
-            //
-            //
-            let mut job_id_a = job_id.write().await;
-            let mut worker_id_a = worker_id.write().await;
+            let mut job = None;
             boilerplate_parse_ipc(
                 |msg| {
                     if let IPCData::FromBackground(bg) = msg {
                         if let Message::ExternalQueueJobResponse(q) = bg.message {
-                            *job_id_a = Some(q.job_id);
-                            *worker_id_a = Some(q.worker_id);
+                            job = Some((JobId::from_raw(q.job_id), WorkerId::from_raw(q.worker_id)));
                             return false;
                         }
                     }
                     true
                 },
-                tx.subscribe(),
+                rx,
                 Duration::from_secs(3),
             )
             .await
-            .unwrap();
-            //
-            bg_com
-                .send(IPCData::new_from_main(
-                    Message::DirectWorkerCommunication(DirectWorkerCommunication {
-                        job_id: job_id_a.clone().unwrap(),
-                        worker_id: worker_id_a.clone().unwrap(),
-                        guild_id: guild_id.clone(),
-                        voice_channel_id: Some(voice_channel_id),
-                        play_audio_url: None,
-                        action_type: DWCActionType::JoinChannel,
-                        request_id: Some(nanoid!()),
-                        new_volume: None,
-                        seek_position: None,
-                        loop_times: None,
-                    }),
-                    tx.clone(),
-                    guild_id,
-                ))
-                .context(FailedToSendIPCRequestSnafu)
-                .unwrap();
-        });
+            .context(TimedOutWaitingForJobCreationConfirmationSnafu)?;
+
+            let (job_id, worker_id) = job.context(NoActiveJobSnafu)?;
+            *self.job_id.write().await = Some(job_id);
+            *self.worker_id.write().await = Some(worker_id);
+        }
+
+        let (job_id, worker_id) = self.job().await.context(NoActiveJobSnafu)?;
+        self.check_supported(&worker_id, &DWCActionType::JoinChannel)?;
+        self.bg_com_tx
+            .send(IPCData::new_from_main(
+                Message::DirectWorkerCommunication(DirectWorkerCommunication {
+                    job_id: job_id.into(),
+                    worker_id: worker_id.into(),
+                    guild_id: self.guild_id.clone().into(),
+                    voice_channel_id: Some(voice_channel_id.clone().into()),
+                    play_audio_url: None,
+                    action_type: DWCActionType::JoinChannel,
+                    request_id: Some(RequestId::new().into()),
+                    new_volume: None,
+                    seek_position: None,
+                    loop_times: None,
+                }),
+                self.tx.clone(),
+                self.guild_id.clone(),
+            ))
+            .context(FailedToSendIPCSnafu)?;
+
+        *self.voice_channel_id.write().await = Some(voice_channel_id);
 
         Ok(())
     }
     /// Exit voice channel
     async fn exit_channel(&self) -> Result<(), ChannelManagerError> {
+        let (job_id, worker_id) = self.job().await.context(NoJobSnafu)?;
+        self.check_supported(&worker_id, &DWCActionType::LeaveChannel)?;
         self.bg_com_tx
             .send(IPCData::new_from_main(
                 Message::DirectWorkerCommunication(DirectWorkerCommunication {
-                    job_id: self.job_id.read().await.clone().unwrap(),
+                    job_id: job_id.into(),
                     action_type: DWCActionType::LeaveChannel,
                     play_audio_url: None,
-                    guild_id: self.guild_id.clone(),
-                    request_id: Some(nanoid!()),
+                    guild_id: self.guild_id.clone().into(),
+                    request_id: Some(RequestId::new().into()),
                     new_volume: None,
                     seek_position: None,
                     loop_times: None,
-                    worker_id: self.worker_id.read().await.clone().unwrap(),
+                    worker_id: worker_id.into(),
                     voice_channel_id: None,
                 }),
                 self.tx.clone(),
@@ -149,6 +146,9 @@ impl ChannelManager for PlayerObject {
             ))
             .context(FailedToSendIPCRequestSnafu)?;
 
+        *self.voice_channel_id.write().await = None;
+        self.listeners.write().await.clear();
+
         Ok(())
     }
 }
@@ -0,0 +1,68 @@
+use crate::actions::player::{PlayerActionError, TrackSource};
+use crate::PlayerObject;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// A track that was played on a PlayerObject
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    pub source: TrackSource,
+    /// Unix timestamp of when playback started
+    pub started_at: Duration,
+    /// Unix timestamp of when playback ended. None if the track is still playing
+    pub ended_at: Option<Duration>,
+    /// Whether the track was stopped or replaced before it finished
+    pub skipped: bool,
+}
+
+#[async_trait]
+/// Keeps track of previously played tracks and allows going back to them
+pub trait HistoryManager {
+    /// Replay the track that was playing before the current one.
+    /// The current track is put back at the front of the queue, and calling this again goes further back.
+    /// Returns false if there is no previous track
+    async fn previous(&self) -> Result<bool, PlayerActionError>;
+    /// Get the played tracks, oldest first. Only the most recent tracks are kept
+    async fn history(&self) -> Vec<HistoryEntry>;
+}
+
+#[async_trait]
+impl HistoryManager for PlayerObject {
+    async fn previous(&self) -> Result<bool, PlayerActionError> {
+        let (index, previous, current) = {
+            let playback = self.playback.read().await;
+            let index = match playback.history_cursor {
+                // Already replaying, go one further back
+                Some(cursor) => cursor.checked_sub(1),
+                None => {
+                    // Skip over the entry of the track that is currently playing
+                    let skip = match playback.history.back() {
+                        Some(entry) if entry.ended_at.is_none() => 1,
+                        _ => 0,
+                    };
+                    playback.history.len().checked_sub(skip + 1)
+                }
+            };
+            let Some(index) = index else {
+                return Ok(false);
+            };
+            (
+                index,
+                playback.history[index].source.clone(),
+                playback.now_playing.clone(),
+            )
+        };
+
+        let request_id = self.send_play(&previous).await?;
+        self.playback.write().await.replay(index, request_id);
+        if let Some(current) = current {
+            self.queue.write().await.push_front(current);
+        }
+        self.start_queue_runner().await;
+        Ok(true)
+    }
+    async fn history(&self) -> Vec<HistoryEntry> {
+        self.playback.read().await.history.iter().cloned().collect()
+    }
+}
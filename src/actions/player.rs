@@ -1,65 +1,175 @@
 use async_trait::async_trait;
 use hearth_interconnect::messages::Message;
 use hearth_interconnect::worker_communication::{DWCActionType, DirectWorkerCommunication};
+use std::time::Duration;
 
 use crate::background::processor::IPCData;
+use crate::capabilities::CapabilityError;
+use crate::ids::{RequestId, UserId};
+use crate::permissions::PermissionDenied;
 use crate::PlayerObject;
-use nanoid::nanoid;
+use serde::{Deserialize, Serialize};
 use snafu::prelude::*;
 use tokio::sync::broadcast::error::SendError;
 
 #[derive(Debug, Snafu)]
 pub enum PlayerActionError {
+    #[snafu(display("Player has no active job, join a channel first"))]
+    NoActiveJob,
     #[snafu(display("Failed to send IPC request to Background thread"))]
-    FailedToSendIPCRequest { source: SendError<IPCData> },
+    FailedToSendIPCRequest {
+        #[snafu(source(from(SendError<IPCData>, Box::new)))]
+        source: Box<SendError<IPCData>>,
+    },
+    #[snafu(context(false), display("Worker can't run this action"))]
+    Unsupported { source: CapabilityError },
+    #[snafu(context(false), display("User isn't allowed to run this action"))]
+    Denied { source: PermissionDenied },
+}
+
+/// Where the Hearth server should fetch a track from
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SourceType {
+    /// Direct link to an audio file
+    Http,
+    /// Youtube video URL
+    Youtube,
+}
+
+/// A playable track along with any information that is known about it ahead of time
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TrackSource {
+    pub url: String,
+    pub source_type: SourceType,
+    /// Title of the track if known. Usually taken from a playlist file
+    pub title: Option<String>,
+    /// Artist of the track if known. Usually taken from a playlist file
+    #[serde(default)]
+    pub artist: Option<String>,
+    /// Duration of the track if known. Usually taken from a playlist file
+    pub duration: Option<Duration>,
+    /// User that requested the track
+    #[serde(default)]
+    pub requester: Option<UserId>,
+}
+
+impl TrackSource {
+    /// Creates a source that plays from an HTTP URL
+    pub fn http(url: String) -> Self {
+        TrackSource {
+            url,
+            source_type: SourceType::Http,
+            title: None,
+            artist: None,
+            duration: None,
+            requester: None,
+        }
+    }
+    /// Creates a source that plays from a Youtube URL
+    pub fn youtube(url: String) -> Self {
+        TrackSource {
+            url,
+            source_type: SourceType::Youtube,
+            title: None,
+            artist: None,
+            duration: None,
+            requester: None,
+        }
+    }
+    /// Creates a source from a URL, picking Youtube playback for Youtube links and HTTP for anything else
+    pub fn from_url(url: String) -> Self {
+        let host = url
+            .split_once("://")
+            .map(|(_, rest)| rest)
+            .unwrap_or(&url)
+            .split(['/', '?', '#'])
+            .next()
+            .unwrap_or_default()
+            .rsplit('@')
+            .next()
+            .unwrap_or_default()
+            .split(':')
+            .next()
+            .unwrap_or_default()
+            .to_ascii_lowercase();
+
+        match host.as_str() {
+            "youtube.com" | "www.youtube.com" | "m.youtube.com" | "music.youtube.com"
+            | "youtu.be" => TrackSource::youtube(url),
+            _ => TrackSource::http(url),
+        }
+    }
+    pub fn with_title(mut self, title: String) -> Self {
+        self.title = Some(title);
+        self
+    }
+    pub fn with_artist(mut self, artist: String) -> Self {
+        self.artist = Some(artist);
+        self
+    }
+    pub fn with_requester(mut self, requester: UserId) -> Self {
+        self.requester = Some(requester);
+        self
+    }
+    pub fn with_duration(mut self, duration: Duration) -> Self {
+        self.duration = Some(duration);
+        self
+    }
 }
 
 #[async_trait]
 /// Allows you to start playback using an HttpRequest or from a Youtube URL
 pub trait Player {
     /// Play from an HTTP URL
-    async fn play_from_http(&mut self, url: String) -> Result<(), PlayerActionError>;
+    async fn play_from_http(&self, url: String) -> Result<(), PlayerActionError>;
     /// Play from a Youtube URL
-    async fn play_from_youtube(&mut self, url: String) -> Result<(), PlayerActionError>;
+    async fn play_from_youtube(&self, url: String) -> Result<(), PlayerActionError>;
+    /// Play from a [`TrackSource`], replacing whatever is currently playing
+    async fn play_source(&self, source: TrackSource) -> Result<(), PlayerActionError>;
 }
 
 #[async_trait]
 impl Player for PlayerObject {
-    async fn play_from_http(&mut self, url: String) -> Result<(), PlayerActionError> {
-        self.bg_com_tx
-            .send(IPCData::new_from_main(
-                Message::DirectWorkerCommunication(DirectWorkerCommunication {
-                    job_id: self.job_id.read().await.clone().unwrap(),
-                    action_type: DWCActionType::PlayDirectLink,
-                    play_audio_url: Some(url),
-                    guild_id: self.guild_id.clone(),
-                    request_id: Some(nanoid!()),
-                    new_volume: None,
-                    seek_position: None,
-                    loop_times: None,
-                    worker_id: self.worker_id.clone().read().await.clone().unwrap(),
-                    voice_channel_id: None,
-                }),
-                self.tx.clone(),
-                self.guild_id.clone(),
-            ))
-            .context(FailedToSendIPCRequestSnafu)?;
+    async fn play_from_http(&self, url: String) -> Result<(), PlayerActionError> {
+        self.play_source(TrackSource::http(url)).await
+    }
+    async fn play_from_youtube(&self, url: String) -> Result<(), PlayerActionError> {
+        self.play_source(TrackSource::youtube(url)).await
+    }
+    async fn play_source(&self, source: TrackSource) -> Result<(), PlayerActionError> {
+        let request_id = self.send_play(&source).await?;
+        self.playback.write().await.start(source, request_id);
 
         Ok(())
     }
-    async fn play_from_youtube(&mut self, url: String) -> Result<(), PlayerActionError> {
+}
+
+impl PlayerObject {
+    /// Tell the Hearth server to play a source without updating the local playback state
+    pub(crate) async fn send_play(
+        &self,
+        source: &TrackSource,
+    ) -> Result<RequestId, PlayerActionError> {
+        let action_type = match source.source_type {
+            SourceType::Http => DWCActionType::PlayDirectLink,
+            SourceType::Youtube => DWCActionType::PlayFromYoutube,
+        };
+
+        let (job_id, worker_id) = self.job().await.context(NoActiveJobSnafu)?;
+        self.check_supported(&worker_id, &action_type)?;
+        let request_id = RequestId::new();
         self.bg_com_tx
             .send(IPCData::new_from_main(
                 Message::DirectWorkerCommunication(DirectWorkerCommunication {
-                    job_id: self.job_id.clone().read().await.clone().unwrap(),
-                    action_type: DWCActionType::PlayFromYoutube,
-                    play_audio_url: Some(url),
-                    guild_id: self.guild_id.clone(),
-                    request_id: Some(nanoid!()),
+                    job_id: job_id.into(),
+                    action_type,
+                    play_audio_url: Some(source.url.clone()),
+                    guild_id: self.guild_id.clone().into(),
+                    request_id: Some(request_id.clone().into()),
                     new_volume: None,
                     seek_position: None,
                     loop_times: None,
-                    worker_id: self.worker_id.clone().read().await.clone().unwrap(),
+                    worker_id: worker_id.into(),
                     voice_channel_id: None,
                 }),
                 self.tx.clone(),
@@ -67,6 +177,6 @@ impl Player for PlayerObject {
             ))
             .context(FailedToSendIPCRequestSnafu)?;
 
-        Ok(())
+        Ok(request_id)
     }
 }
@@ -0,0 +1,366 @@
+use crate::actions::history::HistoryEntry;
+use crate::actions::player::{Player, PlayerActionError, TrackSource};
+use crate::actions::track_manager::TrackManager;
+use crate::background::processor::IPCData;
+use crate::constants::{
+    HISTORY_LIMIT, METADATA_RETRY_INTERVAL, QUEUE_POLL_INTERVAL, UNKNOWN_DURATION_TIMEOUT,
+};
+use crate::helpers::get_unix_timestamp;
+use crate::ids::RequestId;
+use crate::PlayerObject;
+use async_trait::async_trait;
+use log::error;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast::error::TryRecvError;
+use tokio::time::sleep;
+
+/// How the track that is currently playing is being looped
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LoopMode {
+    Off,
+    Forever,
+    /// Loop the given amount of additional times
+    Times(usize),
+}
+
+/// Local estimate of what the Hearth server is currently playing for a PlayerObject
+pub(crate) struct PlaybackState {
+    pub(crate) now_playing: Option<TrackSource>,
+    /// Position reached before `resumed_at`
+    position: Duration,
+    /// When playback was last started or resumed, None while paused
+    resumed_at: Option<Instant>,
+    pub(crate) loop_mode: LoopMode,
+    /// Volume last set through `set_playback_volume`
+    pub(crate) volume: f32,
+    /// Incremented whenever the current track changes
+    pub(crate) track_number: u64,
+    /// When the duration of the current track was last requested
+    metadata_requested_at: Option<Instant>,
+    /// Whether Hearth answered a metadata request for the current track
+    metadata_answered: bool,
+    /// Request ID of the message that started the current track, used to match error reports
+    play_request_id: Option<RequestId>,
+    runner_active: bool,
+    /// Tracks played so far, oldest first. The last entry is still open while its track plays
+    pub(crate) history: VecDeque<HistoryEntry>,
+    /// Index of the history entry being replayed by `previous()`, None while playing new tracks
+    pub(crate) history_cursor: Option<usize>,
+}
+
+impl PlaybackState {
+    pub(crate) fn new() -> Self {
+        PlaybackState {
+            now_playing: None,
+            position: Duration::ZERO,
+            resumed_at: None,
+            loop_mode: LoopMode::Off,
+            volume: 1.0,
+            track_number: 0,
+            metadata_requested_at: None,
+            metadata_answered: false,
+            play_request_id: None,
+            runner_active: false,
+            history: VecDeque::new(),
+            history_cursor: None,
+        }
+    }
+    pub(crate) fn start(&mut self, source: TrackSource, request_id: RequestId) {
+        self.end_current(true);
+        self.track_number += 1;
+        self.history.push_back(HistoryEntry {
+            source: source.clone(),
+            started_at: get_unix_timestamp(),
+            ended_at: None,
+            skipped: false,
+        });
+        while self.history.len() > HISTORY_LIMIT {
+            self.history.pop_front();
+        }
+        self.history_cursor = None;
+        self.play(source, request_id);
+    }
+    /// Play the history entry at `index` again without recording a new entry
+    pub(crate) fn replay(&mut self, index: usize, request_id: RequestId) {
+        let Some(entry) = self.history.get(index) else {
+            return;
+        };
+        let source = entry.source.clone();
+        self.end_current(true);
+        self.track_number += 1;
+        self.history_cursor = Some(index);
+        self.play(source, request_id);
+    }
+    fn play(&mut self, source: TrackSource, request_id: RequestId) {
+        self.now_playing = Some(source);
+        self.position = Duration::ZERO;
+        self.resumed_at = Some(Instant::now());
+        self.loop_mode = LoopMode::Off;
+        self.reset_metadata();
+        self.play_request_id = Some(request_id);
+    }
+    pub(crate) fn stop(&mut self) {
+        self.end_current(true);
+        self.track_number += 1;
+        self.now_playing = None;
+        self.position = Duration::ZERO;
+        self.resumed_at = None;
+        self.play_request_id = None;
+    }
+    pub(crate) fn pause(&mut self) {
+        self.position = self.position();
+        self.resumed_at = None;
+    }
+    pub(crate) fn resume(&mut self) {
+        if self.resumed_at.is_none() {
+            self.resumed_at = Some(Instant::now());
+        }
+    }
+    pub(crate) fn seek(&mut self, position: Duration) {
+        self.position = position;
+        if self.resumed_at.is_some() {
+            self.resumed_at = Some(Instant::now());
+        }
+    }
+    pub(crate) fn restore(
+        &mut self,
+        now_playing: Option<TrackSource>,
+        position: Duration,
+        loop_mode: LoopMode,
+        history: Vec<HistoryEntry>,
+    ) {
+        self.now_playing = now_playing;
+        self.track_number += 1;
+        self.position = position;
+        self.resumed_at = self.now_playing.as_ref().map(|_| Instant::now());
+        self.loop_mode = loop_mode;
+        self.reset_metadata();
+        self.play_request_id = None;
+        self.history = history.into();
+        self.history_cursor = None;
+    }
+    fn reset_metadata(&mut self) {
+        self.metadata_requested_at = None;
+        self.metadata_answered = false;
+    }
+    /// Close the history entry of the current track. Does nothing if it was already closed
+    pub(crate) fn end_current(&mut self, skipped: bool) {
+        if let Some(entry) = self.history.back_mut() {
+            if entry.ended_at.is_none() {
+                entry.ended_at = Some(get_unix_timestamp());
+                entry.skipped = skipped;
+            }
+        }
+    }
+    /// Estimated position in the current track
+    pub(crate) fn position(&self) -> Duration {
+        match self.resumed_at {
+            Some(resumed_at) => self.position + resumed_at.elapsed(),
+            None => self.position,
+        }
+    }
+    /// Returns true once the current track has played for its full duration.
+    /// If Hearth never reported the duration the track ends after `UNKNOWN_DURATION_TIMEOUT`,
+    /// live streams Hearth answered for without a duration keep playing until they are skipped
+    fn finished(&self) -> bool {
+        match &self.now_playing {
+            Some(track) => match track.duration {
+                Some(duration) => self.position() >= duration,
+                None => !self.metadata_answered && self.position() >= UNKNOWN_DURATION_TIMEOUT,
+            },
+            None => false,
+        }
+    }
+    /// Whether the duration of the current track should be requested from Hearth
+    fn needs_metadata(&self) -> bool {
+        let unknown_duration = self
+            .now_playing
+            .as_ref()
+            .is_some_and(|track| track.duration.is_none());
+        let recently_requested = matches!(
+            self.metadata_requested_at,
+            Some(at) if at.elapsed() < METADATA_RETRY_INTERVAL
+        );
+        unknown_duration && !recently_requested
+    }
+}
+
+#[async_trait]
+/// Client-side queue that feeds tracks to the Hearth server one at a time
+pub trait QueueManager {
+    /// Add a track to the end of the queue. If nothing is playing the track starts right away
+    async fn enqueue(&self, source: TrackSource) -> Result<(), PlayerActionError>;
+    /// Stop the current track and start the next one in the queue
+    async fn skip(&self) -> Result<(), PlayerActionError>;
+    /// Remove all tracks waiting in the queue. The current track keeps playing
+    async fn clear_queue(&self) -> Result<(), PlayerActionError>;
+    /// Get the tracks waiting in the queue
+    async fn queued_tracks(&self) -> Vec<TrackSource>;
+    /// Get the track that is currently playing
+    async fn now_playing(&self) -> Option<TrackSource>;
+    /// Get the estimated position in the current track
+    async fn position(&self) -> Duration;
+    /// Get the loop mode of the current track
+    async fn loop_mode(&self) -> LoopMode;
+    /// Get the volume last set on this player
+    async fn volume(&self) -> f32;
+}
+
+#[async_trait]
+impl QueueManager for PlayerObject {
+    async fn enqueue(&self, source: TrackSource) -> Result<(), PlayerActionError> {
+        {
+            // Held until the track is started or queued, so concurrent enqueues can't both start playback
+            let mut playback = self.playback.write().await;
+            if playback.now_playing.is_none() {
+                let request_id = self.send_play(&source).await?;
+                playback.start(source, request_id);
+            } else {
+                self.queue.write().await.push_back(source);
+            }
+        }
+        self.start_queue_runner().await;
+        Ok(())
+    }
+    async fn skip(&self) -> Result<(), PlayerActionError> {
+        let next = self.queue.write().await.pop_front();
+        match next {
+            Some(next) => self.play_source(next).await,
+            None => {
+                if let Err(e) = self.pause_playback().await {
+                    error!("Failed to stop playback when skipping with error: {}", e);
+                }
+                self.playback.write().await.stop();
+                Ok(())
+            }
+        }
+    }
+    async fn clear_queue(&self) -> Result<(), PlayerActionError> {
+        self.queue.write().await.clear();
+        Ok(())
+    }
+    async fn queued_tracks(&self) -> Vec<TrackSource> {
+        self.queue.read().await.iter().cloned().collect()
+    }
+    async fn now_playing(&self) -> Option<TrackSource> {
+        self.playback.read().await.now_playing.clone()
+    }
+    async fn position(&self) -> Duration {
+        self.playback.read().await.position()
+    }
+    async fn loop_mode(&self) -> LoopMode {
+        self.playback.read().await.loop_mode
+    }
+    async fn volume(&self) -> f32 {
+        self.playback.read().await.volume
+    }
+}
+
+impl PlayerObject {
+    /// Starts the background task that moves on to the next queued track once the current one ends.
+    /// The task exits on its own once the queue is drained
+    pub(crate) async fn start_queue_runner(&self) {
+        {
+            let mut playback = self.playback.write().await;
+            if playback.runner_active {
+                return;
+            }
+            playback.runner_active = true;
+        }
+
+        let player = self.clone();
+        let mut rx = self.tx.subscribe();
+        let task = tokio::spawn(async move {
+            loop {
+                sleep(QUEUE_POLL_INTERVAL).await;
+
+                // Learn track durations and positions from any metadata results,
+                // and end tracks Hearth failed to play
+                let mut failed = false;
+                loop {
+                    match rx.try_recv() {
+                        Ok(IPCData::MetadataResult(metadata)) => {
+                            let mut playback = player.playback.write().await;
+                            playback.metadata_answered = true;
+                            if let Some(position) = metadata.position {
+                                playback.seek(Duration::from_secs(position));
+                            }
+                            if let (Some(track), Some(duration)) =
+                                (playback.now_playing.as_mut(), metadata.duration)
+                            {
+                                track.duration.get_or_insert(Duration::from_secs(duration));
+                            }
+                        }
+                        Ok(IPCData::ErrorReport(report)) => {
+                            let playback = player.playback.read().await;
+                            if playback
+                                .play_request_id
+                                .as_ref()
+                                .is_some_and(|id| id.as_str() == report.request_id)
+                            {
+                                error!("Hearth failed to play track: {}", report.error);
+                                failed = true;
+                            }
+                        }
+                        Ok(_) | Err(TryRecvError::Lagged(_)) => {}
+                        Err(_) => break,
+                    }
+                }
+
+                let (finished, needs_metadata) = {
+                    let mut playback = player.playback.write().await;
+                    let needs_metadata = playback.needs_metadata();
+                    if needs_metadata {
+                        playback.metadata_requested_at = Some(Instant::now());
+                    }
+                    if failed {
+                        playback.loop_mode = LoopMode::Off;
+                    }
+                    let finished = failed || playback.now_playing.is_none() || playback.finished();
+                    if finished && playback.now_playing.is_some() {
+                        match playback.loop_mode {
+                            LoopMode::Forever => {
+                                playback.seek(Duration::ZERO);
+                                continue;
+                            }
+                            LoopMode::Times(times) if times > 0 => {
+                                playback.loop_mode = LoopMode::Times(times - 1);
+                                playback.seek(Duration::ZERO);
+                                continue;
+                            }
+                            _ => {}
+                        }
+                    }
+                    (finished, needs_metadata)
+                };
+
+                if needs_metadata {
+                    if let Err(e) = player.get_metadata().await {
+                        error!("Failed to request track duration with error: {}", e);
+                    }
+                }
+
+                if finished {
+                    player.playback.write().await.end_current(false);
+                    let next = player.queue.write().await.pop_front();
+                    match next {
+                        Some(next) => {
+                            if let Err(e) = player.play_source(next).await {
+                                error!("Failed to play next queued track with error: {}", e);
+                            }
+                        }
+                        None => {
+                            let mut playback = player.playback.write().await;
+                            playback.stop();
+                            playback.runner_active = false;
+                            break;
+                        }
+                    }
+                }
+            }
+        });
+        self.track_task(task);
+    }
+}
@@ -4,37 +4,40 @@ use std::time::Duration;
 use hearth_interconnect::errors::ErrorReport;
 use hearth_interconnect::messages::Metadata;
 use log::error;
-use crate::background::processor::IPCData;
+use crate::background::processor::{IPCData, SendFailure};
 use crate::PlayerObject;
 use tokio::time::sleep;
 
 pub trait CharcoalEventHandler {
     fn handle_error(&self, report: ErrorReport);
     fn handle_metadata_response(&self, metadata: Metadata);
+    /// Called when a message from this PlayerObject could not be sent to Hearth
+    fn handle_send_failure(&self, failure: SendFailure) {
+        error!("Failed to send message to Hearth with error: {}", failure.error);
+    }
 }
 
 impl PlayerObject {
     /// Register an error callback that will be called if an error occurs on this PlayerObject
     pub async fn register_event_handler(
-        &mut self,
+        &self,
         event_handler: impl CharcoalEventHandler + Send + 'static,
     ) {
         let mut t_rx = self.tx.subscribe();
         let guild_id = self.guild_id.clone();
-        tokio::spawn(async move {
+        let task = tokio::spawn(async move {
             loop {
                 let x = t_rx.try_recv();
                 match x {
                     Ok(d) => match d {
-                        IPCData::ErrorReport(error_report) => {
-                            if guild_id == error_report.guild_id {
-                                event_handler.handle_error(error_report);
-                            }
+                        IPCData::ErrorReport(error_report) if guild_id.as_str() == error_report.guild_id => {
+                            event_handler.handle_error(error_report);
+                        }
+                        IPCData::MetadataResult(metadata) if guild_id.as_str() == metadata.guild_id => {
+                            event_handler.handle_metadata_response(metadata);
                         }
-                        IPCData::MetadataResult(metadata) => {
-                            if guild_id == metadata.guild_id {
-                                event_handler.handle_metadata_response(metadata);
-                            }
+                        IPCData::SendFailed(failure) if guild_id == failure.guild_id => {
+                            event_handler.handle_send_failure(failure);
                         }
                         _ => {}
                     },
@@ -47,5 +50,6 @@ impl PlayerObject {
                 sleep(Duration::from_millis(250)).await; // Don't max out the CPU
             }
         });
+        self.track_task(task);
     }
 }
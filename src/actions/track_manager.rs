@@ -2,19 +2,31 @@ use async_trait::async_trait;
 use hearth_interconnect::messages::Message;
 use hearth_interconnect::worker_communication::{DWCActionType, DirectWorkerCommunication};
 use std::time::Duration;
-use nanoid::nanoid;
+use crate::actions::queue::LoopMode;
 use crate::background::connector::BoilerplateParseIPCError;
 use crate::background::processor::IPCData;
+use crate::capabilities::CapabilityError;
+use crate::ids::RequestId;
+use crate::permissions::PermissionDenied;
 use crate::PlayerObject;
 use snafu::prelude::*;
 use tokio::sync::broadcast::error::SendError;
 
 #[derive(Debug, Snafu)]
 pub enum TrackActionError {
+    #[snafu(display("Player has no active job, join a channel first"))]
+    NoActiveJob,
     #[snafu(display("Failed to send IPC request to Background thread"))]
-    FailedToSendIPCRequest { source: SendError<IPCData> },
+    FailedToSendIPCRequest {
+        #[snafu(source(from(SendError<IPCData>, Box::new)))]
+        source: Box<SendError<IPCData>>,
+    },
     #[snafu(display("Did not receive metadata result within timeout time-frame"))]
     TimedOutWaitingForMetadataResult { source: BoilerplateParseIPCError },
+    #[snafu(context(false), display("Worker can't run this action"))]
+    Unsupported { source: CapabilityError },
+    #[snafu(context(false), display("User isn't allowed to run this action"))]
+    Denied { source: PermissionDenied },
 }
 
 #[async_trait]
@@ -35,177 +47,200 @@ pub trait TrackManager {
     /// Pause playback
     async fn pause_playback(&self) -> Result<(), TrackActionError>;
     /// Get metadata for track currently being played
-    async fn get_metadata(&mut self) -> Result<(), TrackActionError>;
+    async fn get_metadata(&self) -> Result<(), TrackActionError>;
 }
 #[async_trait]
 impl TrackManager for PlayerObject {
     async fn set_playback_volume(&self, playback_volume: f32) -> Result<(), TrackActionError> {
+        let (job_id, worker_id) = self.job().await.context(NoActiveJobSnafu)?;
+        self.check_supported(&worker_id, &DWCActionType::SetPlaybackVolume)?;
         self.bg_com_tx
             .send(IPCData::new_from_main(
                 Message::DirectWorkerCommunication(DirectWorkerCommunication {
-                    job_id: self.job_id.read().await.clone().unwrap(),
+                    job_id: job_id.into(),
                     action_type: DWCActionType::SetPlaybackVolume,
                     play_audio_url: None,
-                    guild_id: self.guild_id.clone(),
-                    request_id: Some(nanoid!()),
+                    guild_id: self.guild_id.clone().into(),
+                    request_id: Some(RequestId::new().into()),
                     new_volume: Some(playback_volume),
                     seek_position: None,
                     loop_times: None,
-                    worker_id: self.worker_id.read().await.clone().unwrap(),
+                    worker_id: worker_id.into(),
                     voice_channel_id: None,
                 }),
                 self.tx.clone(),
                 self.guild_id.clone(),
             ))
             .context(FailedToSendIPCRequestSnafu)?;
+        self.playback.write().await.volume = playback_volume;
         Ok(())
     }
     async fn force_stop_loop(&self) -> Result<(), TrackActionError> {
+        let (job_id, worker_id) = self.job().await.context(NoActiveJobSnafu)?;
+        self.check_supported(&worker_id, &DWCActionType::ForceStopLoop)?;
         self.bg_com_tx
             .send(IPCData::new_from_main(
                 Message::DirectWorkerCommunication(DirectWorkerCommunication {
-                    job_id: self.job_id.read().await.clone().unwrap(),
+                    job_id: job_id.into(),
                     action_type: DWCActionType::ForceStopLoop,
                     play_audio_url: None,
-                    guild_id: self.guild_id.clone(),
-                    request_id: Some(nanoid!()),
+                    guild_id: self.guild_id.clone().into(),
+                    request_id: Some(RequestId::new().into()),
                     new_volume: None,
                     seek_position: None,
                     loop_times: None,
-                    worker_id: self.worker_id.read().await.clone().unwrap(),
+                    worker_id: worker_id.into(),
                     voice_channel_id: None,
                 }),
                 self.tx.clone(),
                 self.guild_id.clone(),
             ))
             .context(FailedToSendIPCRequestSnafu)?;
+        self.playback.write().await.loop_mode = LoopMode::Off;
 
         Ok(())
     }
     async fn loop_indefinitely(&self) -> Result<(), TrackActionError> {
+        let (job_id, worker_id) = self.job().await.context(NoActiveJobSnafu)?;
+        self.check_supported(&worker_id, &DWCActionType::LoopForever)?;
         self.bg_com_tx
             .send(IPCData::new_from_main(
                 Message::DirectWorkerCommunication(DirectWorkerCommunication {
-                    job_id: self.job_id.read().await.clone().unwrap(),
+                    job_id: job_id.into(),
                     action_type: DWCActionType::LoopForever,
                     play_audio_url: None,
-                    guild_id: self.guild_id.clone(),
-                    request_id: Some(nanoid!()),
+                    guild_id: self.guild_id.clone().into(),
+                    request_id: Some(RequestId::new().into()),
                     new_volume: None,
                     seek_position: None,
                     loop_times: None,
-                    worker_id: self.worker_id.read().await.clone().unwrap(),
+                    worker_id: worker_id.into(),
                     voice_channel_id: None,
                 }),
                 self.tx.clone(),
                 self.guild_id.clone(),
             ))
             .context(FailedToSendIPCRequestSnafu)?;
+        self.playback.write().await.loop_mode = LoopMode::Forever;
 
         Ok(())
     }
 
     async fn loop_x_times(&self, times: usize) -> Result<(), TrackActionError> {
+        let (job_id, worker_id) = self.job().await.context(NoActiveJobSnafu)?;
+        self.check_supported(&worker_id, &DWCActionType::LoopXTimes)?;
         self.bg_com_tx
             .send(IPCData::new_from_main(
                 Message::DirectWorkerCommunication(DirectWorkerCommunication {
-                    job_id: self.job_id.read().await.clone().unwrap(),
+                    job_id: job_id.into(),
                     action_type: DWCActionType::LoopXTimes,
                     play_audio_url: None,
-                    guild_id: self.guild_id.clone(),
-                    request_id: Some(nanoid!()),
+                    guild_id: self.guild_id.clone().into(),
+                    request_id: Some(RequestId::new().into()),
                     new_volume: None,
                     seek_position: None,
                     loop_times: Some(times),
-                    worker_id: self.worker_id.read().await.clone().unwrap(),
+                    worker_id: worker_id.into(),
                     voice_channel_id: None,
                 }),
                 self.tx.clone(),
                 self.guild_id.clone(),
             ))
             .context(FailedToSendIPCRequestSnafu)?;
+        self.playback.write().await.loop_mode = LoopMode::Times(times);
 
         Ok(())
     }
     async fn seek_to_position(&self, position: Duration) -> Result<(), TrackActionError> {
+        let (job_id, worker_id) = self.job().await.context(NoActiveJobSnafu)?;
+        self.check_supported(&worker_id, &DWCActionType::SeekToPosition)?;
         self.bg_com_tx
             .send(IPCData::new_from_main(
                 Message::DirectWorkerCommunication(DirectWorkerCommunication {
-                    job_id: self.job_id.read().await.clone().unwrap(),
+                    job_id: job_id.into(),
                     action_type: DWCActionType::SeekToPosition,
                     play_audio_url: None,
-                    guild_id: self.guild_id.clone(),
-                    request_id: Some(nanoid!()),
+                    guild_id: self.guild_id.clone().into(),
+                    request_id: Some(RequestId::new().into()),
                     new_volume: None,
                     seek_position: Some(position.as_millis() as u64),
                     loop_times: None,
-                    worker_id: self.worker_id.read().await.clone().unwrap(),
+                    worker_id: worker_id.into(),
                     voice_channel_id: None,
                 }),
                 self.tx.clone(),
                 self.guild_id.clone(),
             ))
             .context(FailedToSendIPCRequestSnafu)?;
+        self.playback.write().await.seek(position);
 
         Ok(())
     }
     async fn resume_playback(&self) -> Result<(), TrackActionError> {
+        let (job_id, worker_id) = self.job().await.context(NoActiveJobSnafu)?;
+        self.check_supported(&worker_id, &DWCActionType::ResumePlayback)?;
         self.bg_com_tx
             .send(IPCData::new_from_main(
                 Message::DirectWorkerCommunication(DirectWorkerCommunication {
-                    job_id: self.job_id.read().await.clone().unwrap(),
+                    job_id: job_id.into(),
                     action_type: DWCActionType::ResumePlayback,
                     play_audio_url: None,
-                    guild_id: self.guild_id.clone(),
-                    request_id: Some(nanoid!()),
+                    guild_id: self.guild_id.clone().into(),
+                    request_id: Some(RequestId::new().into()),
                     new_volume: None,
                     seek_position: None,
                     loop_times: None,
-                    worker_id: self.worker_id.read().await.clone().unwrap(),
+                    worker_id: worker_id.into(),
                     voice_channel_id: None,
                 }),
                 self.tx.clone(),
                 self.guild_id.clone(),
             ))
             .context(FailedToSendIPCRequestSnafu)?;
+        self.playback.write().await.resume();
 
         Ok(())
     }
     async fn pause_playback(&self) -> Result<(), TrackActionError> {
+        let (job_id, worker_id) = self.job().await.context(NoActiveJobSnafu)?;
+        self.check_supported(&worker_id, &DWCActionType::PausePlayback)?;
         self.bg_com_tx
             .send(IPCData::new_from_main(
                 Message::DirectWorkerCommunication(DirectWorkerCommunication {
-                    job_id: self.job_id.read().await.clone().unwrap(),
+                    job_id: job_id.into(),
                     action_type: DWCActionType::PausePlayback,
                     play_audio_url: None,
-                    guild_id: self.guild_id.clone(),
-                    request_id: Some(nanoid!()),
+                    guild_id: self.guild_id.clone().into(),
+                    request_id: Some(RequestId::new().into()),
                     new_volume: None,
                     seek_position: None,
                     loop_times: None,
-                    worker_id: self.worker_id.read().await.clone().unwrap(),
+                    worker_id: worker_id.into(),
                     voice_channel_id: None,
                 }),
                 self.tx.clone(),
                 self.guild_id.clone(),
             ))
             .context(FailedToSendIPCRequestSnafu)?;
+        self.playback.write().await.pause();
 
         Ok(())
     }
-    async fn get_metadata(&mut self) -> Result<(), TrackActionError> {
+    async fn get_metadata(&self) -> Result<(), TrackActionError> {
+        let (job_id, worker_id) = self.job().await.context(NoActiveJobSnafu)?;
+        self.check_supported(&worker_id, &DWCActionType::GetMetaData)?;
         self.bg_com_tx
             .send(IPCData::new_from_main(
                 Message::DirectWorkerCommunication(DirectWorkerCommunication {
-                    job_id: self.job_id.read().await.clone().unwrap(),
+                    job_id: job_id.into(),
                     action_type: DWCActionType::GetMetaData,
                     play_audio_url: None,
-                    guild_id: self.guild_id.clone(),
-                    request_id: Some(nanoid!()),
+                    guild_id: self.guild_id.clone().into(),
+                    request_id: Some(RequestId::new().into()),
                     new_volume: None,
                     seek_position: None,
                     loop_times: None,
-                    worker_id: self.worker_id.read().await.clone().unwrap(),
+                    worker_id: worker_id.into(),
                     voice_channel_id: None,
                 }),
                 self.tx.clone(),
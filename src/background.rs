@@ -1,2 +1,4 @@
 pub mod connector;
+pub mod outbox;
 pub mod processor;
+pub(crate) mod ownership;
@@ -1,75 +1,330 @@
 // Internal connector
 use crate::background::processor::IPCData;
+use crate::config::{OAuthTokenProvider, PemSource, RecordKey, SaslMechanism};
+use crate::constants::{
+    CLIENT_ID_HEADER, CLIENT_VERSION, CLIENT_VERSION_HEADER, FAILURE_DETAIL_HEADER,
+    FAILURE_KIND_HEADER, MESSAGE_TYPE_HEADER, PROTOCOL_VERSION, PROTOCOL_VERSION_HEADER,
+    REQUEST_ID_HEADER, SOURCE_OFFSET_HEADER, SOURCE_PARTITION_HEADER, SOURCE_TOPIC_HEADER,
+};
+use crate::diagnostics::BadRecord;
 use crate::helpers::get_unix_timestamp;
-use crate::CharcoalConfig;
+use crate::ids::RequestId;
+use crate::{CharcoalConfig, ConfigError};
 use hearth_interconnect::messages::Message;
 use log::error;
-use nanoid::nanoid;
-use rdkafka::consumer::{BaseConsumer, Consumer};
-use rdkafka::producer::{FutureProducer, FutureRecord};
-use rdkafka::ClientConfig;
+use rdkafka::client::{ClientContext, OAuthToken as KafkaOAuthToken};
+use rdkafka::consumer::{BaseConsumer, Consumer, ConsumerContext};
+use rdkafka::error::KafkaError;
+use rdkafka::message::{Header, OwnedHeaders};
+use rdkafka::producer::{DeliveryFuture, FutureProducer, FutureRecord};
+use rdkafka::{ClientConfig, Message as KafkaMessage};
 use snafu::prelude::*;
+use std::collections::HashMap;
+use std::error::Error;
 use std::ops::Sub;
-use std::time::Duration;
+use std::sync::Arc;
+use std::time::{Duration, UNIX_EPOCH};
 use tokio::sync::broadcast::error::TryRecvError;
 use tokio::sync::broadcast::Receiver;
 use tokio::time::sleep;
 
-fn configure_kafka_ssl(mut kafka_config: ClientConfig, config: &CharcoalConfig) -> ClientConfig {
-    if config.ssl.is_some() {
-        let ssl = config.ssl.clone().unwrap();
-        kafka_config
-            .set("security.protocol", "ssl")
-            .set("ssl.ca.location", ssl.ssl_ca)
-            .set("ssl.certificate.location", ssl.ssl_cert)
-            .set("ssl.key.location", ssl.ssl_key);
-    } else if config.sasl.is_some() {
-        let sasl = config.sasl.clone().unwrap();
-        kafka_config
-            .set("security.protocol", "SASL_SSL")
-            .set("sasl.mechanisms", "PLAIN")
-            .set("sasl.username", sasl.kafka_username)
-            .set("sasl.password", sasl.kafka_password);
+/// Consumer Charcoal reads Hearth messages from
+pub type CharcoalConsumer = BaseConsumer<KafkaContext>;
+/// Producer Charcoal sends messages to Hearth with
+pub type CharcoalProducer = FutureProducer<KafkaContext>;
+
+/// Kafka client context, supplies OAUTHBEARER tokens when that SASL mechanism is used
+pub struct KafkaContext {
+    oauth_token_provider: Option<Arc<dyn OAuthTokenProvider>>,
+}
+
+impl KafkaContext {
+    fn new(config: &CharcoalConfig) -> Self {
+        KafkaContext {
+            oauth_token_provider: config
+                .sasl
+                .as_ref()
+                .and_then(|s| s.oauth_token_provider.clone()),
+        }
     }
-    kafka_config
 }
 
-pub fn initialize_producer(broker: &str, config: &CharcoalConfig) -> FutureProducer {
-    let mut kafka_config = ClientConfig::new().set("bootstrap.servers", broker).clone();
+impl ClientContext for KafkaContext {
+    const ENABLE_REFRESH_OAUTH_TOKEN: bool = true;
 
-    kafka_config = configure_kafka_ssl(kafka_config, config);
+    fn generate_oauth_token(
+        &self,
+        _oauthbearer_config: Option<&str>,
+    ) -> Result<KafkaOAuthToken, Box<dyn Error>> {
+        let provider = self
+            .oauth_token_provider
+            .as_ref()
+            .ok_or("No OAUTHBEARER token provider configured")?;
+        let token = provider.token().map_err(|e| -> Box<dyn Error> { e })?;
+        let lifetime = token
+            .expires_at
+            .duration_since(UNIX_EPOCH)
+            .map_err(|_| "OAUTHBEARER token expires before the Unix epoch")?;
+        Ok(KafkaOAuthToken {
+            token: token.token,
+            principal_name: token.principal_name,
+            lifetime_ms: lifetime.as_millis() as i64,
+        })
+    }
+}
 
-    let producer: FutureProducer = kafka_config.create().expect("Failed to create Producer");
+impl ConsumerContext for KafkaContext {}
 
-    producer
+fn configure_kafka_security(kafka_config: &mut ClientConfig, config: &CharcoalConfig) {
+    kafka_config.set("security.protocol", config.security_protocol().as_str());
+    if let Some(ssl) = &config.ssl {
+        for (name, source) in [
+            ("ssl.ca", &ssl.ssl_ca),
+            ("ssl.certificate", &ssl.ssl_cert),
+            ("ssl.key", &ssl.ssl_key),
+        ] {
+            match source {
+                PemSource::Path(path) => kafka_config.set(format!("{}.location", name), path),
+                PemSource::Pem { pem } => kafka_config.set(format!("{}.pem", name), pem),
+            };
+        }
+    }
+    if let Some(sasl) = &config.sasl {
+        kafka_config.set("sasl.mechanisms", sasl.mechanism.as_str());
+        if sasl.mechanism != SaslMechanism::OAuthBearer {
+            kafka_config
+                .set("sasl.username", &sasl.kafka_username)
+                .set("sasl.password", &sasl.kafka_password);
+        }
+    }
+}
+
+/// Apply preset properties and then the user's properties, so the user's win
+fn apply_properties(
+    kafka_config: &mut ClientConfig,
+    preset: Option<&[(&str, &str)]>,
+    properties: &HashMap<String, String>,
+) {
+    for (key, value) in preset.unwrap_or_default() {
+        kafka_config.set(*key, *value);
+    }
+    for (key, value) in properties {
+        kafka_config.set(key, value);
+    }
+}
+
+#[derive(Debug, Snafu)]
+pub enum InitError {
+    #[snafu(display("Invalid Charcoal config"), visibility(pub(crate)))]
+    InvalidConfig { source: ConfigError },
+    #[snafu(display("Failed to create Kafka producer"))]
+    FailedToCreateProducer { source: KafkaError },
+    #[snafu(display("Failed to create Kafka consumer"))]
+    FailedToCreateConsumer { source: KafkaError },
+    #[snafu(display("Failed to subscribe to Kafka topic {topic}"))]
+    FailedToSubscribe { source: KafkaError, topic: String },
+    #[snafu(display("Background thread has stopped"))]
+    BackgroundThreadStopped,
+}
+
+pub fn initialize_producer(config: &CharcoalConfig) -> Result<CharcoalProducer, InitError> {
+    let mut kafka_config = ClientConfig::new()
+        .set("bootstrap.servers", config.bootstrap_servers())
+        // Give up quickly, failed sends are retried by the outbox
+        .set("message.timeout.ms", "5000")
+        .clone();
+
+    configure_kafka_security(&mut kafka_config, config);
+    apply_properties(
+        &mut kafka_config,
+        config.preset.map(|p| p.producer_properties()),
+        &config.producer_properties,
+    );
+
+    kafka_config
+        .create_with_context(KafkaContext::new(config))
+        .context(FailedToCreateProducerSnafu)
 }
 
-pub async fn initialize_client(brokers: &String, config: &CharcoalConfig) -> BaseConsumer {
+pub async fn initialize_client(config: &CharcoalConfig) -> Result<CharcoalConsumer, InitError> {
     let mut kafka_config = ClientConfig::new()
-        .set("group.id", nanoid!())
-        .set("bootstrap.servers", brokers)
+        .set("group.id", config.group_id.group_id())
+        .set("auto.offset.reset", config.offset_reset.as_str())
+        .set("bootstrap.servers", config.bootstrap_servers())
         .set("enable.partition.eof", "false")
         .set("session.timeout.ms", "6000")
         .set("enable.auto.commit", "true")
-        .set("security.protocol", "ssl")
         .clone();
 
-    kafka_config = configure_kafka_ssl(kafka_config, config);
+    configure_kafka_security(&mut kafka_config, config);
+    apply_properties(
+        &mut kafka_config,
+        config.preset.map(|p| p.consumer_properties()),
+        &config.consumer_properties,
+    );
 
-    let consumer: BaseConsumer = kafka_config.create().expect("Failed to create Consumer");
+    let consumer: CharcoalConsumer = kafka_config
+        .create_with_context(KafkaContext::new(config))
+        .context(FailedToCreateConsumerSnafu)?;
 
     consumer
-        .subscribe(&[&config.kafka_topic])
-        .expect("Can't subscribe to specified topic");
+        .subscribe(&[config.inbound_topic()])
+        .context(FailedToSubscribeSnafu {
+            topic: config.inbound_topic(),
+        })?;
 
-    consumer
+    Ok(consumer)
+}
+
+#[derive(Debug, Snafu)]
+pub enum SendMessageError {
+    #[snafu(display("Failed to serialize message"))]
+    FailedToSerialize { source: serde_json::Error },
+    #[snafu(display("Failed to deliver message to Kafka"))]
+    FailedToDeliver { source: KafkaError },
+    #[snafu(display("Outbound buffer is full ({capacity} messages)"))]
+    BufferFull { capacity: usize },
+    #[snafu(display("Message expired before it could be sent"))]
+    Expired,
+    #[snafu(display("Kafka is unreachable, not sending messages until it recovers"))]
+    CircuitOpen,
+}
+
+/// Request ID of a message sent to Hearth
+pub(crate) fn request_id(message: &Message) -> Option<RequestId> {
+    match message {
+        Message::ExternalQueueJob(j) => Some(RequestId::from_raw(j.request_id.as_str())),
+        Message::DirectWorkerCommunication(d) => d.request_id.as_deref().map(RequestId::from_raw),
+        _ => None,
+    }
+}
+
+/// Name of the message type, sent in the `message-type` header
+pub(crate) fn message_type(message: &Message) -> &'static str {
+    match message {
+        Message::InternalWorkerAnalytics(_) => "InternalWorkerAnalytics",
+        Message::InternalWorkerQueueJob(_) => "InternalWorkerQueueJob",
+        Message::InternalPingPongRequest => "InternalPingPongRequest",
+        Message::InternalPongResponse(_) => "InternalPongResponse",
+        Message::ExternalQueueJob(_) => "ExternalQueueJob",
+        Message::ExternalQueueJobResponse(_) => "ExternalQueueJobResponse",
+        Message::ExternalMetadataResult(_) => "ExternalMetadataResult",
+        Message::ExternalJobExpired(_) => "ExternalJobExpired",
+        Message::DirectWorkerCommunication(_) => "DirectWorkerCommunication",
+        Message::ErrorReport(_) => "ErrorReport",
+        Message::WorkerShutdownAlert(_) => "WorkerShutdownAlert",
+    }
 }
 
-pub async fn send_message(message: &Message, topic: &str, producer: &mut FutureProducer) {
+/// Record key that keeps all messages for one player on the same partition
+fn record_key(message: &Message, record_key: RecordKey) -> Option<&str> {
+    match message {
+        Message::ExternalQueueJob(j) => Some(&j.guild_id),
+        Message::DirectWorkerCommunication(d) => match record_key {
+            RecordKey::Guild => Some(&d.guild_id),
+            RecordKey::Job => Some(&d.job_id),
+        },
+        _ => None,
+    }
+}
+
+/// Hand a message to the producer without waiting for Kafka to acknowledge it
+pub(crate) fn enqueue_message(
+    message: &Message,
+    config: &CharcoalConfig,
+    producer: &CharcoalProducer,
+) -> Result<DeliveryFuture, SendMessageError> {
     // Send message to worker
-    let data = serde_json::to_string(message).unwrap();
-    let record: FutureRecord<String, String> = FutureRecord::to(topic).payload(&data);
-    producer.send(record, Duration::from_secs(1)).await.unwrap();
+    let data = serde_json::to_string(message).context(FailedToSerializeSnafu)?;
+    let request_id = request_id(message);
+    let headers = OwnedHeaders::new()
+        .insert(Header {
+            key: MESSAGE_TYPE_HEADER,
+            value: Some(message_type(message)),
+        })
+        .insert(Header {
+            key: REQUEST_ID_HEADER,
+            value: request_id.as_ref().map(RequestId::as_str),
+        })
+        .insert(Header {
+            key: CLIENT_VERSION_HEADER,
+            value: Some(CLIENT_VERSION),
+        })
+        .insert(Header {
+            key: PROTOCOL_VERSION_HEADER,
+            value: Some(PROTOCOL_VERSION),
+        })
+        .insert(Header {
+            key: CLIENT_ID_HEADER,
+            value: config.client_id.as_deref(),
+        });
+    let topic = config.topic_for(message);
+    let mut record: FutureRecord<str, String> =
+        FutureRecord::to(&topic).payload(&data).headers(headers);
+    if let Some(key) = record_key(message, config.record_key) {
+        record = record.key(key);
+    }
+    producer
+        .send_result(record)
+        .map_err(|(e, _)| e)
+        .context(FailedToDeliverSnafu)
+}
+
+/// Wait for the delivery report of a message handed to the producer by `enqueue_message`
+pub(crate) async fn wait_for_delivery(delivery: DeliveryFuture) -> Result<(), SendMessageError> {
+    match delivery.await {
+        Ok(Ok(_)) => Ok(()),
+        Ok(Err((source, _))) => Err(SendMessageError::FailedToDeliver { source }),
+        // The producer was dropped before the message was delivered
+        Err(_) => Err(SendMessageError::FailedToDeliver {
+            source: KafkaError::Canceled,
+        }),
+    }
+}
+
+/// Forward a record Charcoal could not use to the dead-letter topic, describing the failure in headers.
+/// Returns once the record is handed to the producer, the returned future resolves when it is delivered
+pub(crate) fn send_dead_letter(
+    topic: &str,
+    record: &BadRecord,
+    original: &impl KafkaMessage,
+    producer: &CharcoalProducer,
+) -> Result<DeliveryFuture, KafkaError> {
+    let partition = record.partition.to_string();
+    let offset = record.offset.to_string();
+    let headers = OwnedHeaders::new()
+        .insert(Header {
+            key: FAILURE_KIND_HEADER,
+            value: Some(record.kind.as_str()),
+        })
+        .insert(Header {
+            key: FAILURE_DETAIL_HEADER,
+            value: Some(&record.detail),
+        })
+        .insert(Header {
+            key: SOURCE_TOPIC_HEADER,
+            value: Some(&record.topic),
+        })
+        .insert(Header {
+            key: SOURCE_PARTITION_HEADER,
+            value: Some(&partition),
+        })
+        .insert(Header {
+            key: SOURCE_OFFSET_HEADER,
+            value: Some(&offset),
+        })
+        .insert(Header {
+            key: CLIENT_VERSION_HEADER,
+            value: Some(CLIENT_VERSION),
+        });
+    let mut dead_letter: FutureRecord<[u8], [u8]> = FutureRecord::to(topic).headers(headers);
+    if let Some(payload) = original.payload() {
+        dead_letter = dead_letter.payload(payload);
+    }
+    if let Some(key) = original.key() {
+        dead_letter = dead_letter.key(key);
+    }
+    producer.send_result(dead_letter).map_err(|(e, _)| e)
 }
 
 #[derive(Debug, Snafu)]
@@ -0,0 +1,307 @@
+//! Buffers messages for Hearth while Kafka is unreachable.
+//!
+//! Every message that is due is handed to Kafka in order, and the delivery reports are awaited on a
+//! separate task so the background thread keeps processing while Kafka is slow. A new batch is only
+//! sent once the reports of the previous one are in. A failed send is retried with exponential
+//! backoff. While a message waits for its retry, later messages of the same guild wait behind it so
+//! a player's commands stay in order, messages of other guilds are still sent. After enough
+//! consecutive failures the circuit breaker opens and buffered and new messages fail right away
+//! until the cooldown has passed
+
+use crate::background::connector::{
+    enqueue_message, request_id, wait_for_delivery, CharcoalProducer, SendMessageError,
+};
+use crate::background::processor::{IPCData, SendFailure};
+use crate::ids::GuildId;
+use crate::CharcoalConfig;
+use hearth_interconnect::messages::Message;
+use log::{error, info, warn};
+use serde::Deserialize;
+use std::collections::{HashSet, VecDeque};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast::Sender;
+use tokio::sync::oneshot::error::TryRecvError;
+use tokio::sync::{oneshot, watch};
+
+/// Whether Charcoal can currently reach Kafka
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnectionState {
+    Connected,
+    /// Sends are failing and being retried
+    Degraded,
+    /// The outage is confirmed, messages fail right away until the breaker cooldown has passed
+    Down,
+}
+
+/// Retry and circuit breaker settings for messages sent to Hearth.
+/// Messages are kept in order per guild, a message waiting for its retry doesn't hold up other guilds
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct OutboundConfig {
+    /// Most messages that can wait to be sent. Further messages fail right away
+    pub buffer_size: usize,
+    /// Attempts per message before it fails
+    pub max_attempts: u32,
+    pub initial_backoff_ms: u64,
+    pub max_backoff_ms: u64,
+    /// Messages that could not be sent within this many seconds are dropped,
+    /// so old commands such as seeks are not replayed once Kafka is back
+    pub expire_after_secs: Option<u64>,
+    /// Consecutive failed attempts after which the circuit breaker opens.
+    /// Messages sent together count as a single attempt
+    pub breaker_threshold: u32,
+    /// How long the circuit breaker stays open before sending is tried again
+    pub breaker_cooldown_secs: u64,
+}
+
+impl Default for OutboundConfig {
+    fn default() -> Self {
+        OutboundConfig {
+            buffer_size: 256,
+            max_attempts: 5,
+            initial_backoff_ms: 250,
+            max_backoff_ms: 10_000,
+            expire_after_secs: None,
+            breaker_threshold: 5,
+            breaker_cooldown_secs: 30,
+        }
+    }
+}
+
+/// Who is told when a buffered message could not be sent
+pub(crate) enum Origin {
+    /// Sent by a PlayerObject, failures are reported to its event handler
+    Player {
+        response_tx: Arc<Sender<IPCData>>,
+        guild_id: GuildId,
+    },
+    /// The capability handshake, which the background thread sends again later if it fails
+    Handshake,
+}
+
+struct Pending {
+    message: Message,
+    origin: Origin,
+    queued_at: Instant,
+    attempts: u32,
+    next_attempt: Instant,
+}
+
+/// Outcome of every message in a batch, in the order they were sent
+type BatchResult = Vec<(Pending, Result<(), SendMessageError>)>;
+
+pub(crate) struct Outbox {
+    pending: VecDeque<Pending>,
+    /// Delivery reports of the batch that is being sent
+    in_flight: Option<oneshot::Receiver<BatchResult>>,
+    consecutive_failures: u32,
+    /// Set when a handshake could not be sent, until the background thread takes it
+    handshake_failed: bool,
+    /// Set while the circuit breaker is open
+    open_until: Option<Instant>,
+    state: watch::Sender<ConnectionState>,
+}
+
+impl Outbox {
+    pub(crate) fn new(state: watch::Sender<ConnectionState>) -> Self {
+        Outbox {
+            pending: VecDeque::new(),
+            in_flight: None,
+            consecutive_failures: 0,
+            handshake_failed: false,
+            open_until: None,
+            state,
+        }
+    }
+    /// Queue a message to be sent
+    pub(crate) fn push(&mut self, message: Message, origin: Origin, config: &OutboundConfig) {
+        if self.open_until.is_some_and(|until| Instant::now() < until) {
+            self.fail(message, origin, SendMessageError::CircuitOpen);
+            return;
+        }
+        if self.pending.len() >= config.buffer_size {
+            let capacity = config.buffer_size;
+            self.fail(message, origin, SendMessageError::BufferFull { capacity });
+            return;
+        }
+        let now = Instant::now();
+        self.pending.push_back(Pending {
+            message,
+            origin,
+            queued_at: now,
+            attempts: 0,
+            next_attempt: now,
+        });
+    }
+    /// Whether a handshake failed since the last call
+    pub(crate) fn take_handshake_failure(&mut self) -> bool {
+        std::mem::take(&mut self.handshake_failed)
+    }
+    /// Handle the delivery reports of the last batch, then hand every message that is due to Kafka.
+    /// Never waits for Kafka
+    pub(crate) fn flush(&mut self, config: &CharcoalConfig, producer: &CharcoalProducer) {
+        let outbound = &config.outbound;
+        if let Some(in_flight) = &mut self.in_flight {
+            match in_flight.try_recv() {
+                Ok(results) => {
+                    self.in_flight = None;
+                    self.settle(results, outbound);
+                }
+                Err(TryRecvError::Empty) => return,
+                Err(TryRecvError::Closed) => {
+                    self.in_flight = None;
+                    error!("Lost the delivery reports of messages sent to Hearth");
+                }
+            }
+        }
+
+        let now = Instant::now();
+        let max_age = outbound.expire_after_secs.map(Duration::from_secs);
+        let mut batch = Vec::new();
+        let mut waiting = VecDeque::with_capacity(self.pending.len());
+        // Guilds with a message waiting for its retry
+        let mut blocked = HashSet::new();
+        let mut refused = false;
+        for mut next in std::mem::take(&mut self.pending) {
+            if max_age.is_some_and(|max_age| now.duration_since(next.queued_at) > max_age) {
+                self.fail(next.message, next.origin, SendMessageError::Expired);
+                continue;
+            }
+            let guild_id = match &next.origin {
+                Origin::Player { guild_id, .. } => Some(guild_id.clone()),
+                Origin::Handshake => None,
+            };
+            let guild_blocked = guild_id.as_ref().is_some_and(|g| blocked.contains(g));
+            // Everything stays in order behind a message the producer refused
+            if refused || guild_blocked || next.next_attempt > now {
+                blocked.extend(guild_id);
+                waiting.push_back(next);
+                continue;
+            }
+            next.attempts += 1;
+            let delivery = enqueue_message(&next.message, config, producer);
+            refused = delivery.is_err();
+            batch.push((next, delivery));
+        }
+        self.pending = waiting;
+        if batch.is_empty() {
+            return;
+        }
+
+        let (tx, rx) = oneshot::channel();
+        tokio::spawn(async move {
+            let mut results = Vec::with_capacity(batch.len());
+            for (pending, delivery) in batch {
+                let result = match delivery {
+                    Ok(delivery) => wait_for_delivery(delivery).await,
+                    Err(e) => Err(e),
+                };
+                results.push((pending, result));
+            }
+            // The outbox is gone if the background thread stopped
+            let _ = tx.send(results);
+        });
+        self.in_flight = Some(rx);
+    }
+    /// Drop delivered messages and schedule retries for the ones that failed
+    fn settle(&mut self, results: BatchResult, config: &OutboundConfig) {
+        let now = Instant::now();
+        let mut delivered = false;
+        let mut retries = Vec::new();
+        let mut failed_delivery = false;
+        for (mut pending, result) in results {
+            match result {
+                Ok(()) => delivered = true,
+                Err(e @ SendMessageError::FailedToDeliver { .. }) => {
+                    failed_delivery = true;
+                    warn!(
+                        "Failed to send message to Hearth (attempt {}): {}",
+                        pending.attempts, e
+                    );
+                    if pending.attempts >= config.max_attempts {
+                        self.fail(pending.message, pending.origin, e);
+                    } else {
+                        let backoff = config
+                            .initial_backoff_ms
+                            .saturating_mul(1 << (pending.attempts - 1).min(16))
+                            .min(config.max_backoff_ms);
+                        pending.next_attempt = now + Duration::from_millis(backoff);
+                        retries.push(pending);
+                    }
+                }
+                // Retrying won't help with anything but delivery failures
+                Err(e) => self.fail(pending.message, pending.origin, e),
+            }
+        }
+        // Retries go back in front of messages that were queued in the meantime
+        for pending in retries.into_iter().rev() {
+            self.pending.push_front(pending);
+        }
+
+        if delivered {
+            self.consecutive_failures = 0;
+            self.open_until = None;
+        }
+        if failed_delivery {
+            self.consecutive_failures += 1;
+            if self.consecutive_failures >= config.breaker_threshold {
+                self.open_breaker(config);
+            } else {
+                self.set_state(ConnectionState::Degraded);
+            }
+        } else if delivered {
+            self.set_state(ConnectionState::Connected);
+        }
+    }
+    /// Fail every buffered message and reject new ones until the cooldown has passed
+    fn open_breaker(&mut self, config: &OutboundConfig) {
+        error!(
+            "Kafka is unreachable, failing messages for {} seconds",
+            config.breaker_cooldown_secs
+        );
+        self.open_until = Some(Instant::now() + Duration::from_secs(config.breaker_cooldown_secs));
+        for pending in std::mem::take(&mut self.pending) {
+            self.fail(
+                pending.message,
+                pending.origin,
+                SendMessageError::CircuitOpen,
+            );
+        }
+        self.set_state(ConnectionState::Down);
+    }
+    fn set_state(&self, state: ConnectionState) {
+        self.state.send_if_modified(|current| {
+            if *current == state {
+                return false;
+            }
+            info!("Kafka connection state changed to {:?}", state);
+            *current = state;
+            true
+        });
+    }
+    /// Tell whoever sent a message that it could not be sent
+    fn fail(&mut self, message: Message, origin: Origin, error: SendMessageError) {
+        match origin {
+            Origin::Player {
+                response_tx,
+                guild_id,
+            } => {
+                let failure = SendFailure {
+                    guild_id,
+                    request_id: request_id(&message),
+                    error: Arc::new(error),
+                };
+                // Nobody may be listening for failures, which is fine
+                let _ = response_tx.send(IPCData::SendFailed(failure));
+            }
+            Origin::Handshake => {
+                warn!(
+                    "Failed to ask Hearth workers for their capabilities: {}",
+                    error
+                );
+                self.handshake_failed = true;
+            }
+        }
+    }
+}
@@ -0,0 +1,78 @@
+//! Tracks which jobs belong to this Charcoal instance, so clients sharing a topic ignore each other's messages
+
+use crate::ids::{GuildId, JobId, WorkerId};
+use hearth_interconnect::messages::Message;
+use std::collections::{HashMap, HashSet};
+
+#[derive(Default)]
+pub(crate) struct Ownership {
+    /// Guilds that requested a job and are waiting for Hearth's response
+    pending: HashSet<GuildId>,
+    /// Job IDs owned by this client with their guild and worker ID
+    jobs: HashMap<String, (GuildId, String)>,
+}
+
+impl Ownership {
+    /// Record a message this client sent to Hearth
+    pub(crate) fn sent(&mut self, message: &Message) {
+        match message {
+            Message::ExternalQueueJob(j) => {
+                self.pending.insert(GuildId::from_raw(j.guild_id.as_str()));
+            }
+            Message::DirectWorkerCommunication(d) => {
+                self.jobs.insert(
+                    d.job_id.clone(),
+                    (GuildId::from_raw(d.guild_id.as_str()), d.worker_id.clone()),
+                );
+            }
+            _ => {}
+        }
+    }
+    /// Record a job this client owns without having sent anything for it yet
+    pub(crate) fn adopt(&mut self, job_id: JobId, guild_id: GuildId, worker_id: WorkerId) {
+        self.jobs
+            .insert(job_id.into(), (guild_id, worker_id.into()));
+    }
+    /// Whether a message from Hearth is meant for this client.
+    /// Shutdown alerts are narrowed down to the guilds of this client
+    pub(crate) fn accept(&mut self, message: &mut Message) -> bool {
+        match message {
+            Message::ExternalQueueJobResponse(r) => {
+                let guild_id = GuildId::from_raw(r.guild_id.as_str());
+                if !self.pending.remove(&guild_id) {
+                    return false;
+                }
+                self.jobs
+                    .insert(r.job_id.clone(), (guild_id, r.worker_id.clone()));
+                true
+            }
+            Message::ErrorReport(e) => {
+                self.jobs.contains_key(&e.job_id)
+                    // Job creation failed
+                    || self.pending.remove(&GuildId::from_raw(e.guild_id.as_str()))
+            }
+            Message::ExternalMetadataResult(m) => self.jobs.contains_key(&m.job_id),
+            Message::ExternalJobExpired(je) => self.jobs.remove(&je.job_id).is_some(),
+            Message::WorkerShutdownAlert(alert) => {
+                let mut owned = HashSet::new();
+                self.jobs.retain(|_, (guild_id, worker_id)| {
+                    if *worker_id != alert.worker_id {
+                        return true;
+                    }
+                    owned.insert(guild_id.clone());
+                    false
+                });
+                alert
+                    .affected_guild_ids
+                    .retain(|g| owned.contains(&GuildId::from_raw(g.as_str())));
+                !alert.affected_guild_ids.is_empty()
+            }
+            _ => true,
+        }
+    }
+    /// Forget everything about a guild once its player is removed
+    pub(crate) fn forget_guild(&mut self, guild_id: &GuildId) {
+        self.pending.remove(guild_id);
+        self.jobs.retain(|_, (g, _)| g != guild_id);
+    }
+}
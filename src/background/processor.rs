@@ -1,15 +1,30 @@
-use crate::background::connector::send_message;
+use crate::background::connector::{
+    message_type, send_dead_letter, CharcoalConsumer, CharcoalProducer, SendMessageError,
+};
+use crate::background::outbox::{ConnectionState, Origin, Outbox};
+use crate::background::ownership::Ownership;
+use crate::capabilities::Capabilities;
+use crate::constants::{
+    CLIENT_ID_HEADER, HANDSHAKE_RETRY_INTERVAL, MAX_RECORDS_PER_POLL, PROTOCOL_VERSION_HEADER,
+    SUPPORTED_ACTIONS_HEADER,
+};
+use crate::diagnostics::{sample_payload, BadRecord, BadRecordKind, Diagnostics};
+use crate::helpers::get_unix_timestamp;
+use crate::ids::{GuildId, JobId, RequestId, WorkerId};
 use crate::CharcoalConfig;
 use hearth_interconnect::errors::ErrorReport;
 use hearth_interconnect::messages::{Message, Metadata};
-use log::{debug, error};
-use rdkafka::consumer::BaseConsumer;
-use rdkafka::producer::FutureProducer;
+use log::{debug, error, info};
+use rdkafka::error::KafkaError;
+use rdkafka::message::Headers;
 use rdkafka::Message as KafkaMessage;
 use std::collections::HashMap;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast::error::TryRecvError;
 use tokio::sync::broadcast::{Receiver, Sender};
+use tokio::sync::mpsc::UnboundedReceiver;
+use tokio::sync::watch;
 
 #[derive(Clone, Debug)]
 pub struct FromBackgroundData {
@@ -20,7 +35,7 @@ pub struct FromBackgroundData {
 pub struct FromMainData {
     pub message: Message,
     pub response_tx: Arc<Sender<IPCData>>,
-    pub guild_id: String,
+    pub guild_id: GuildId,
 }
 
 #[derive(Clone, Debug)]
@@ -29,6 +44,39 @@ pub enum IPCData {
     FromMain(FromMainData),
     ErrorReport(ErrorReport),
     MetadataResult(Metadata),
+    /// Tells the background thread to stop routing messages for a guild
+    RemoveRoute(GuildId),
+    /// A message from a PlayerObject could not be sent to Hearth
+    SendFailed(SendFailure),
+    /// Tells the background thread to ask workers for their capabilities again
+    RequestCapabilities,
+    /// Tells the background thread about a job this client already owns, for example one restored from a session
+    RegisterJob(JobRoute),
+}
+
+/// A job and the PlayerObject that Hearth's messages about it are routed to
+#[derive(Clone, Debug)]
+pub struct JobRoute {
+    pub guild_id: GuildId,
+    pub job_id: JobId,
+    pub worker_id: WorkerId,
+    pub response_tx: Arc<Sender<IPCData>>,
+}
+
+/// Reported to a PlayerObject when one of its messages could not be sent to Hearth
+#[derive(Clone, Debug)]
+pub struct SendFailure {
+    pub guild_id: GuildId,
+    /// Request ID of the failed message, if it had one
+    pub request_id: Option<RequestId>,
+    pub error: Arc<SendMessageError>,
+}
+
+/// Kafka clients built from new credentials that replace the ones used by the background thread
+pub struct KafkaClients {
+    pub(crate) consumer: CharcoalConsumer,
+    pub(crate) producer: CharcoalProducer,
+    pub(crate) config: CharcoalConfig,
 }
 
 // Makes things slightly easier
@@ -36,7 +84,7 @@ impl IPCData {
     pub fn new_from_main(
         message: Message,
         sender: Arc<Sender<IPCData>>,
-        guild_id: String,
+        guild_id: GuildId,
     ) -> IPCData {
         IPCData::FromMain(FromMainData {
             message,
@@ -51,13 +99,13 @@ impl IPCData {
 
 pub async fn parse_message(
     message: Message,
-    guild_id_to_tx: &mut HashMap<String, Arc<Sender<IPCData>>>,
+    guild_id_to_tx: &mut HashMap<GuildId, Arc<Sender<IPCData>>>,
     global_tx: &mut Sender<IPCData>,
 ) {
     match &message {
         Message::ErrorReport(e) => {
             error!("GOT Error: {:?} From Hearth Server", e);
-            let tx = guild_id_to_tx.get_mut(&e.guild_id);
+            let tx = guild_id_to_tx.get_mut(&GuildId::from_raw(e.guild_id.as_str()));
             match tx {
                 Some(tx) => {
                     let gt = tx.send(IPCData::ErrorReport(e.clone()));
@@ -98,7 +146,7 @@ pub async fn parse_message(
             }
         }
         Message::ExternalQueueJobResponse(r) => {
-            let tx = guild_id_to_tx.get_mut(&r.guild_id);
+            let tx = guild_id_to_tx.get_mut(&GuildId::from_raw(r.guild_id.as_str()));
             match tx {
                 Some(tx) => {
                     let r = tx.send(IPCData::new_from_background(message));
@@ -115,7 +163,7 @@ pub async fn parse_message(
             }
         }
         Message::ExternalMetadataResult(metadata) => {
-            let tx = guild_id_to_tx.get_mut(&metadata.guild_id);
+            let tx = guild_id_to_tx.get_mut(&GuildId::from_raw(metadata.guild_id.as_str()));
             match tx {
                 Some(tx) => {
                     let gt = tx.send(IPCData::MetadataResult(metadata.clone()));
@@ -135,57 +183,236 @@ pub async fn parse_message(
     }
 }
 
+/// Whether a message is older than the configured `skip_stale_after_secs`
+fn is_stale(message: &impl KafkaMessage, config: &CharcoalConfig) -> bool {
+    let (Some(max_age), Some(timestamp)) = (
+        config.skip_stale_after_secs,
+        message.timestamp().to_millis(),
+    ) else {
+        return false;
+    };
+    let age_ms = get_unix_timestamp().as_millis() as i64 - timestamp;
+    age_ms > max_age as i64 * 1000
+}
+
+/// Value of a record header, None if the header is missing or not UTF-8
+fn header_str<'a>(message: &'a impl KafkaMessage, key: &str) -> Option<&'a str> {
+    let header = message.headers()?.iter().find(|h| h.key == key)?;
+    std::str::from_utf8(header.value?).ok()
+}
+
+/// Whether a record is meant for this client. Records without a client ID are checked by `Ownership` instead
+fn is_for_client(message: &impl KafkaMessage, config: &CharcoalConfig) -> bool {
+    match header_str(message, CLIENT_ID_HEADER) {
+        Some(client_id) => Some(client_id) == config.client_id.as_deref(),
+        None => true,
+    }
+}
+
+/// Whether `parse_message` handles a message
+fn is_handled(message: &Message) -> bool {
+    matches!(
+        message,
+        Message::ErrorReport(_)
+            | Message::ExternalJobExpired(_)
+            | Message::WorkerShutdownAlert(_)
+            | Message::ExternalQueueJobResponse(_)
+            | Message::ExternalMetadataResult(_)
+    )
+}
+
+/// Count a record Charcoal could not use and forward it to the dead-letter topic if one is configured.
+/// The delivery is awaited on its own task so an unreachable Kafka doesn't hold up the background thread
+fn report_bad_record(
+    record: BadRecord,
+    original: &impl KafkaMessage,
+    diagnostics: &Diagnostics,
+    config: &CharcoalConfig,
+    producer: &CharcoalProducer,
+) {
+    if let Some(topic) = &config.dead_letter_topic {
+        let delivery = send_dead_letter(topic, &record, original, producer);
+        let topic = topic.clone();
+        tokio::spawn(async move {
+            let result = match delivery {
+                Ok(delivery) => match delivery.await {
+                    Ok(Ok(_)) => Ok(()),
+                    Ok(Err((e, _))) => Err(e),
+                    Err(_) => Err(KafkaError::Canceled),
+                },
+                Err(e) => Err(e),
+            };
+            if let Err(e) = result {
+                error!(
+                    "Failed to send record to dead-letter topic {}: {}",
+                    topic, e
+                );
+            }
+        });
+    }
+    diagnostics.record(record);
+}
+
 pub async fn init_processor(
     mut rx: Receiver<IPCData>,
     mut global_tx: Sender<IPCData>,
-    consumer: BaseConsumer,
-    mut producer: FutureProducer,
-    config: CharcoalConfig,
+    mut reconnect_rx: UnboundedReceiver<KafkaClients>,
+    connection_state: watch::Sender<ConnectionState>,
+    clients: KafkaClients,
+    diagnostics: Arc<Diagnostics>,
+    capabilities: Arc<Capabilities>,
 ) {
-    let mut guild_id_to_tx: HashMap<String, Arc<Sender<IPCData>>> = HashMap::new();
+    let KafkaClients {
+        mut consumer,
+        mut producer,
+        mut config,
+    } = clients;
+    let mut guild_id_to_tx: HashMap<GuildId, Arc<Sender<IPCData>>> = HashMap::new();
+    let mut ownership = Ownership::default();
+    let mut outbox = Outbox::new(connection_state);
+    // Stale messages are only skipped until the consumer has caught up
+    let mut catching_up = true;
+    // If enabled, workers are asked for their capabilities on startup and after reconnecting,
+    // a handshake that could not be sent is tried again after `HANDSHAKE_RETRY_INTERVAL`
+    let mut handshake_due = config.capability_handshake;
+    let mut handshake_retry_at: Option<Instant> = None;
     loop {
-        let mss = consumer.poll(Duration::from_millis(25));
-        if let Some(p) = mss {
+        // Swap in new clients after the credentials changed. Routes are kept so players keep working
+        if let Ok(clients) = reconnect_rx.try_recv() {
+            consumer = clients.consumer;
+            producer = clients.producer;
+            config = clients.config;
+            catching_up = true;
+            handshake_due = config.capability_handshake;
+            info!("Reconnected to Kafka with new credentials");
+        }
+        if handshake_retry_at.is_some_and(|at| Instant::now() >= at) {
+            handshake_retry_at = None;
+            handshake_due = true;
+        }
+        if handshake_due {
+            handshake_due = false;
+            outbox.push(
+                Message::InternalPingPongRequest,
+                Origin::Handshake,
+                &config.outbound,
+            );
+        }
+        // Handle every record Kafka has ready, only waiting for the first one
+        let mut poll_timeout = Duration::from_millis(25);
+        for _ in 0..MAX_RECORDS_PER_POLL {
+            let Some(p) = consumer.poll(poll_timeout) else {
+                break;
+            };
+            poll_timeout = Duration::ZERO;
             match p {
+                Ok(m) if catching_up && is_stale(&m, &config) => {
+                    debug!("Skipped stale message at offset {}", m.offset());
+                }
+                Ok(m) if !is_for_client(&m, &config) => {
+                    debug!(
+                        "Skipped message for another client at offset {}",
+                        m.offset()
+                    );
+                }
                 Ok(m) => {
-                    let payload = m.payload();
-
-                    match payload {
-                        Some(payload) => {
-                            let parsed_message: Result<Message, serde_json::Error> =
-                                serde_json::from_slice(payload);
+                    catching_up = false;
+                    let bad_record = |kind, detail| BadRecord {
+                        kind,
+                        topic: m.topic().to_string(),
+                        partition: m.partition(),
+                        offset: m.offset(),
+                        detail,
+                        payload: sample_payload(m.payload().unwrap_or_default()),
+                    };
 
-                            match parsed_message {
-                                Ok(m) => {
-                                    parse_message(m, &mut guild_id_to_tx, &mut global_tx).await;
-                                }
-                                Err(e) => error!("{}", e),
+                    match m.payload().map(serde_json::from_slice::<Message>) {
+                        Some(Ok(Message::InternalPongResponse(pong))) => {
+                            let protocol_version = header_str(&m, PROTOCOL_VERSION_HEADER);
+                            debug!(
+                                "Worker {} speaks protocol {}",
+                                pong.worker_id,
+                                protocol_version.unwrap_or("unknown")
+                            );
+                            capabilities.record(
+                                WorkerId::from_raw(pong.worker_id),
+                                protocol_version,
+                                header_str(&m, SUPPORTED_ACTIONS_HEADER),
+                            );
+                        }
+                        Some(Ok(mut message)) if is_handled(&message) => {
+                            if ownership.accept(&mut message) {
+                                parse_message(message, &mut guild_id_to_tx, &mut global_tx).await;
+                            } else {
+                                debug!("Skipped message for a job of another client");
                             }
                         }
+                        // Requests from Charcoal instances show up here when they share a topic with Hearth's events
+                        Some(Ok(
+                            Message::ExternalQueueJob(_)
+                            | Message::DirectWorkerCommunication(_)
+                            | Message::InternalPingPongRequest,
+                        )) => {}
+                        // Traffic between Hearth's workers on the shared topic, valid but not for Charcoal
+                        Some(Ok(
+                            Message::InternalWorkerAnalytics(_)
+                            | Message::InternalWorkerQueueJob(_),
+                        )) => {}
+                        Some(Ok(message)) => {
+                            let detail = format!("No handler for {}", message_type(&message));
+                            debug!("{}", detail);
+                            let record = bad_record(BadRecordKind::UnhandledMessage, detail);
+                            report_bad_record(record, &m, &diagnostics, &config, &producer);
+                        }
+                        Some(Err(e)) => {
+                            error!("Failed to parse message from Hearth: {}", e);
+                            let record = bad_record(BadRecordKind::InvalidPayload, e.to_string());
+                            report_bad_record(record, &m, &diagnostics, &config, &producer);
+                        }
                         None => {
                             error!("Received No Payload!");
+                            let record = bad_record(BadRecordKind::EmptyPayload, String::new());
+                            report_bad_record(record, &m, &diagnostics, &config, &producer);
                         }
                     }
                 }
                 Err(e) => error!("{}", e),
             }
         }
-        // Receive messages from main function
-        let rx_data = rx.try_recv();
-        match rx_data {
-            Ok(d) => {
-                if let IPCData::FromMain(m) = d {
-                    guild_id_to_tx.insert(m.guild_id, m.response_tx);
-                    send_message(&m.message, &config.kafka_topic, &mut producer).await;
+        // Receive everything the players sent since the last iteration
+        loop {
+            match rx.try_recv() {
+                Ok(IPCData::FromMain(m)) => {
+                    guild_id_to_tx.insert(m.guild_id.clone(), m.response_tx.clone());
+                    ownership.sent(&m.message);
+                    let origin = Origin::Player {
+                        response_tx: m.response_tx,
+                        guild_id: m.guild_id,
+                    };
+                    outbox.push(m.message, origin, &config.outbound);
                 }
-            }
-            Err(e) => {
-                if e.to_string() == "channel empty" {
-                    debug!("Channel empty!");
-                } else {
-                    error!("Receive failed with: {}", e);
+                Ok(IPCData::RemoveRoute(guild_id)) => {
+                    guild_id_to_tx.remove(&guild_id);
+                    ownership.forget_guild(&guild_id);
+                }
+                Ok(IPCData::RequestCapabilities) => handshake_due = config.capability_handshake,
+                Ok(IPCData::RegisterJob(route)) => {
+                    ownership.adopt(route.job_id, route.guild_id.clone(), route.worker_id);
+                    guild_id_to_tx.insert(route.guild_id, route.response_tx);
                 }
+                Ok(_) => {}
+                Err(TryRecvError::Lagged(skipped)) => {
+                    error!(
+                        "Background thread fell behind and lost {} messages",
+                        skipped
+                    );
+                }
+                Err(TryRecvError::Empty | TryRecvError::Closed) => break,
             }
         }
+        outbox.flush(&config, &producer);
+        if outbox.take_handshake_failure() {
+            handshake_retry_at = Some(Instant::now() + HANDSHAKE_RETRY_INTERVAL);
+        }
     }
 }
@@ -0,0 +1,295 @@
+//! Protocol versions and supported actions of Hearth workers.
+//!
+//! With `CharcoalConfig::capability_handshake` set, Charcoal sends a ping with its protocol version in the
+//! record headers when it connects, through the outbound buffer, and sends it again after
+//! `HANDSHAKE_RETRY_INTERVAL` if it could not be delivered.
+//! Workers that answer with `protocol-version` and `supported-actions` headers are recorded here, so
+//! actions they don't support are rejected before they are sent. Workers that answer without these
+//! headers, or have not answered yet, are assumed to support everything.
+//!
+//! Workers built on hearth-interconnect 0.1.0 don't send these headers yet. They show up as
+//! [`Compatibility::Unknown`] and nothing is rejected locally until Hearth starts sending them,
+//! which is why the handshake is off by default
+
+use crate::background::outbox::ConnectionState;
+pub use crate::constants::PROTOCOL_VERSION;
+use crate::ids::WorkerId;
+use crate::PlayerObject;
+use hearth_interconnect::worker_communication::DWCActionType;
+use snafu::prelude::*;
+use std::collections::{HashMap, HashSet};
+use std::sync::RwLock;
+use std::time::SystemTime;
+
+#[derive(Debug, Snafu)]
+pub enum CapabilityError {
+    #[snafu(display("Worker {worker_id} does not support {action}"))]
+    UnsupportedAction { worker_id: WorkerId, action: String },
+    #[snafu(display(
+        "Worker {worker_id} speaks protocol {version}, Charcoal speaks {PROTOCOL_VERSION}"
+    ))]
+    IncompatibleProtocol {
+        worker_id: WorkerId,
+        version: String,
+    },
+}
+
+/// Whether a worker can be used by this version of Charcoal
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Compatibility {
+    Compatible,
+    /// The worker did not report its protocol version
+    Unknown,
+    Incompatible {
+        version: String,
+    },
+}
+
+/// What a worker reported about itself
+#[derive(Clone, Debug)]
+pub struct WorkerCapabilities {
+    pub worker_id: WorkerId,
+    pub protocol_version: Option<String>,
+    /// Names of the supported `DWCActionType`s, None if the worker did not report them
+    pub supported_actions: Option<HashSet<String>>,
+    pub last_seen: SystemTime,
+}
+
+impl WorkerCapabilities {
+    pub fn compatibility(&self) -> Compatibility {
+        match &self.protocol_version {
+            None => Compatibility::Unknown,
+            Some(version) if same_protocol(version, PROTOCOL_VERSION) => Compatibility::Compatible,
+            Some(version) => Compatibility::Incompatible {
+                version: version.clone(),
+            },
+        }
+    }
+    pub fn supports(&self, action: &DWCActionType) -> bool {
+        match &self.supported_actions {
+            Some(actions) => actions.contains(&action_name(action)),
+            None => true,
+        }
+    }
+}
+
+/// Versions are compatible if they share the major version, or the minor version before 1.0
+fn same_protocol(a: &str, b: &str) -> bool {
+    let parts = |v: &str| {
+        v.trim()
+            .split('.')
+            .map(|p| p.parse::<u64>().unwrap_or(u64::MAX))
+            .collect::<Vec<_>>()
+    };
+    let (a, b) = (parts(a), parts(b));
+    match (a.first(), b.first()) {
+        (Some(0), Some(0)) => a.get(1) == b.get(1),
+        (major_a, major_b) => major_a == major_b,
+    }
+}
+
+/// Name of an action as used in the `supported-actions` header
+pub(crate) fn action_name(action: &DWCActionType) -> String {
+    format!("{:?}", action)
+}
+
+/// Capabilities of every worker that answered Charcoal's handshake
+#[derive(Default)]
+pub struct Capabilities {
+    workers: RwLock<HashMap<WorkerId, WorkerCapabilities>>,
+}
+
+impl Capabilities {
+    pub fn worker(&self, worker_id: &WorkerId) -> Option<WorkerCapabilities> {
+        self.workers.read().unwrap().get(worker_id).cloned()
+    }
+    pub fn workers(&self) -> Vec<WorkerCapabilities> {
+        self.workers.read().unwrap().values().cloned().collect()
+    }
+    /// Record a worker's answer to the handshake. `supported_actions` is a comma separated list
+    pub(crate) fn record(
+        &self,
+        worker_id: WorkerId,
+        protocol_version: Option<&str>,
+        supported_actions: Option<&str>,
+    ) {
+        let capabilities = WorkerCapabilities {
+            worker_id: worker_id.clone(),
+            protocol_version: protocol_version.map(str::to_string),
+            supported_actions: supported_actions.map(|actions| {
+                actions
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|a| !a.is_empty())
+                    .map(str::to_string)
+                    .collect()
+            }),
+            last_seen: SystemTime::now(),
+        };
+        self.workers
+            .write()
+            .unwrap()
+            .insert(worker_id, capabilities);
+    }
+    /// Check that a worker can run an action. Unknown workers are allowed
+    pub(crate) fn check(
+        &self,
+        worker_id: &WorkerId,
+        action: &DWCActionType,
+    ) -> Result<(), CapabilityError> {
+        let Some(worker) = self.worker(worker_id) else {
+            return Ok(());
+        };
+        if let Compatibility::Incompatible { version } = worker.compatibility() {
+            return IncompatibleProtocolSnafu { worker_id, version }.fail();
+        }
+        ensure!(
+            worker.supports(action),
+            UnsupportedActionSnafu {
+                worker_id,
+                action: action_name(action),
+            }
+        );
+        Ok(())
+    }
+}
+
+/// Snapshot of Charcoal's connection to Kafka and the workers it knows about
+#[derive(Clone, Debug)]
+pub struct Health {
+    pub connection: ConnectionState,
+    pub protocol_version: &'static str,
+    pub workers: Vec<WorkerCapabilities>,
+}
+
+impl Health {
+    /// Kafka is reachable and no known worker speaks an incompatible protocol
+    pub fn is_healthy(&self) -> bool {
+        self.connection == ConnectionState::Connected
+            && self
+                .workers
+                .iter()
+                .all(|w| !matches!(w.compatibility(), Compatibility::Incompatible { .. }))
+    }
+}
+
+impl PlayerObject {
+    /// Reject an action the worker running this player's job does not support
+    pub(crate) fn check_supported(
+        &self,
+        worker_id: &WorkerId,
+        action: &DWCActionType,
+    ) -> Result<(), CapabilityError> {
+        self.capabilities.check(worker_id, action)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::actions::track_manager::TrackManager;
+    use crate::background::processor::IPCData;
+    use crate::ids::{GuildId, JobId};
+    use hearth_interconnect::messages::Message;
+    use hearth_interconnect::worker_communication::DirectWorkerCommunication;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::sync::broadcast;
+
+    #[test]
+    fn protocol_versions() {
+        assert!(same_protocol("0.1", "0.1.0"));
+        assert!(same_protocol(" 0.1.3 ", "0.1.0"));
+        assert!(!same_protocol("0.2.0", "0.1.0"));
+        assert!(same_protocol("1.0", "1.5.2"));
+        assert!(!same_protocol("2.0.0", "1.0.0"));
+        // Parts that aren't numbers are read as u64::MAX, so they only match other unparseable parts
+        assert!(!same_protocol("0.x", "0.1.0"));
+        assert!(!same_protocol("latest", "0.1.0"));
+        assert!(same_protocol("0.x", "0.y"));
+    }
+
+    #[test]
+    fn check_workers() {
+        let capabilities = Capabilities::default();
+        let unknown = WorkerId::from_raw("unknown");
+        assert!(capabilities
+            .check(&unknown, &DWCActionType::PlayDirectLink)
+            .is_ok());
+
+        // Workers running hearth-interconnect 0.1.0 answer without headers
+        let legacy = WorkerId::from_raw("legacy");
+        capabilities.record(legacy.clone(), None, None);
+        assert_eq!(
+            capabilities.worker(&legacy).unwrap().compatibility(),
+            Compatibility::Unknown
+        );
+        assert!(capabilities
+            .check(&legacy, &DWCActionType::SeekToPosition)
+            .is_ok());
+
+        let limited = WorkerId::from_raw("limited");
+        let actions = format!(
+            "{}, {}",
+            action_name(&DWCActionType::PlayDirectLink),
+            action_name(&DWCActionType::PausePlayback)
+        );
+        capabilities.record(limited.clone(), Some(PROTOCOL_VERSION), Some(&actions));
+        assert!(capabilities
+            .check(&limited, &DWCActionType::PausePlayback)
+            .is_ok());
+        assert!(matches!(
+            capabilities.check(&limited, &DWCActionType::SeekToPosition),
+            Err(CapabilityError::UnsupportedAction { .. })
+        ));
+
+        let outdated = WorkerId::from_raw("outdated");
+        capabilities.record(outdated.clone(), Some("0.0.9"), None);
+        assert!(matches!(
+            capabilities.check(&outdated, &DWCActionType::PlayDirectLink),
+            Err(CapabilityError::IncompatibleProtocol { version, .. }) if version == "0.0.9"
+        ));
+    }
+
+    #[tokio::test]
+    async fn unknown_workers_get_every_action() {
+        let capabilities = Arc::new(Capabilities::default());
+        let (bg_tx, mut bg_rx) = broadcast::channel(16);
+        let player = PlayerObject::new(
+            GuildId::from_raw("1"),
+            bg_tx,
+            Arc::new(std::sync::RwLock::new(None)),
+            capabilities.clone(),
+        );
+        let worker_id = WorkerId::from_raw("worker");
+        *player.job_id.write().await = Some(JobId::from_raw("job"));
+        *player.worker_id.write().await = Some(worker_id.clone());
+
+        // What a hearth-interconnect 0.1.0 worker answers the handshake with
+        capabilities.record(worker_id.clone(), None, None);
+        let worker = capabilities.worker(&worker_id).unwrap();
+        assert_eq!(worker.compatibility(), Compatibility::Unknown);
+        assert!(worker.supports(&DWCActionType::SeekToPosition));
+        assert!(Health {
+            connection: ConnectionState::Connected,
+            protocol_version: PROTOCOL_VERSION,
+            workers: capabilities.workers(),
+        }
+        .is_healthy());
+
+        player
+            .seek_to_position(Duration::from_secs(30))
+            .await
+            .unwrap();
+        let Ok(IPCData::FromMain(sent)) = bg_rx.try_recv() else {
+            panic!("seek was not sent to the background thread");
+        };
+        assert!(matches!(
+            sent.message,
+            Message::DirectWorkerCommunication(DirectWorkerCommunication {
+                action_type: DWCActionType::SeekToPosition,
+                ..
+            })
+        ));
+    }
+}
@@ -0,0 +1,1058 @@
+//! Charcoal configuration, loadable from the environment or a TOML file and validated before connecting
+
+use crate::background::outbox::OutboundConfig;
+use hearth_interconnect::messages::Message;
+use nanoid::nanoid;
+use openssl::error::ErrorStack;
+use openssl::pkey::PKey;
+use openssl::x509::X509;
+use serde::Deserialize;
+use snafu::prelude::*;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+/// Longest topic name Kafka accepts
+const MAX_TOPIC_LENGTH: usize = 249;
+
+#[derive(Debug, Snafu)]
+pub enum ConfigError {
+    #[snafu(display("No Kafka brokers configured"))]
+    NoBrokers,
+    #[snafu(display("Invalid Kafka broker {broker:?}, expected host:port"))]
+    InvalidBroker { broker: String },
+    #[snafu(display("Invalid Kafka topic {topic:?}: {reason}"))]
+    InvalidTopic { topic: String, reason: String },
+    #[snafu(display("SSL {name} file {} does not exist", path.display()))]
+    MissingSslFile { name: String, path: PathBuf },
+    #[snafu(display("Failed to read SSL {name} file {}", path.display()))]
+    FailedToReadSslFile {
+        source: std::io::Error,
+        name: String,
+        path: PathBuf,
+    },
+    #[snafu(display("SSL {name} is not valid PEM"))]
+    InvalidPem { source: ErrorStack, name: String },
+    #[snafu(display("SSL CA does not contain any certificates"))]
+    NoCaCertificates,
+    #[snafu(display("SSL key does not belong to the SSL certificate"))]
+    KeyMismatch,
+    #[snafu(display("Environment variable {name} is not set"))]
+    MissingEnvVar { name: String },
+    #[snafu(display("Environment variable {name} has invalid value {value:?}"))]
+    InvalidEnvVar { name: String, value: String },
+    #[snafu(display("Security protocol {protocol} {reason}"))]
+    IncompatibleSecurity {
+        protocol: SecurityProtocol,
+        reason: String,
+    },
+    #[snafu(display("SASL mechanism {mechanism} requires a username and password"))]
+    MissingSaslCredentials { mechanism: SaslMechanism },
+    #[snafu(display("SASL mechanism OAUTHBEARER requires a token provider"))]
+    MissingOAuthTokenProvider,
+    #[snafu(display("Failed to read config file {}", path.display()))]
+    FailedToReadConfig {
+        source: std::io::Error,
+        path: PathBuf,
+    },
+    #[snafu(display("Failed to parse config file {}", path.display()))]
+    InvalidToml {
+        source: toml::de::Error,
+        path: PathBuf,
+    },
+}
+
+#[derive(Clone, Deserialize)]
+/// Stores SSL Config for Kafka
+pub struct SSLConfig {
+    /// SSL key file or PEM data
+    pub ssl_key: PemSource,
+    /// SSL CA file or PEM data
+    pub ssl_ca: PemSource,
+    /// SSL cert file or PEM data
+    pub ssl_cert: PemSource,
+}
+
+impl SSLConfig {
+    /// Check that the CA, certificate and key parse and that the key belongs to the certificate
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let ca = self.ssl_ca.load("CA")?;
+        let ca_certs = X509::stack_from_pem(&ca).context(InvalidPemSnafu { name: "CA" })?;
+        ensure!(!ca_certs.is_empty(), NoCaCertificatesSnafu);
+
+        let cert =
+            X509::from_pem(&self.ssl_cert.load("certificate")?).context(InvalidPemSnafu {
+                name: "certificate",
+            })?;
+        let key = PKey::private_key_from_pem(&self.ssl_key.load("key")?)
+            .context(InvalidPemSnafu { name: "key" })?;
+        let cert_key = cert.public_key().context(InvalidPemSnafu {
+            name: "certificate",
+        })?;
+        ensure!(cert_key.public_eq(&key), KeyMismatchSnafu);
+        Ok(())
+    }
+}
+
+/// PEM encoded SSL data, either read from a file or given directly.
+/// In config files a string is a path, PEM data is given as `{ pem = "..." }`
+#[derive(Clone, Deserialize)]
+#[serde(untagged)]
+pub enum PemSource {
+    Path(String),
+    Pem { pem: String },
+}
+
+impl PemSource {
+    pub fn path(path: impl Into<String>) -> Self {
+        PemSource::Path(path.into())
+    }
+    pub fn pem(pem: impl Into<String>) -> Self {
+        PemSource::Pem { pem: pem.into() }
+    }
+    /// Treat the value as PEM data if it starts with a PEM header and as a path otherwise
+    pub fn detect(value: impl Into<String>) -> Self {
+        let value = value.into();
+        match value.trim_start().starts_with("-----BEGIN") {
+            true => PemSource::Pem { pem: value },
+            false => PemSource::Path(value),
+        }
+    }
+    /// Read the PEM data, from disk if this is a path
+    fn load(&self, name: &str) -> Result<Vec<u8>, ConfigError> {
+        match self {
+            PemSource::Path(path) => {
+                ensure!(
+                    Path::new(path).is_file(),
+                    MissingSslFileSnafu { name, path }
+                );
+                std::fs::read(path).context(FailedToReadSslFileSnafu { name, path })
+            }
+            PemSource::Pem { pem } => Ok(pem.clone().into_bytes()),
+        }
+    }
+}
+
+impl From<String> for PemSource {
+    fn from(path: String) -> Self {
+        PemSource::Path(path)
+    }
+}
+
+impl From<&str> for PemSource {
+    fn from(path: &str) -> Self {
+        PemSource::Path(path.to_string())
+    }
+}
+
+/// How Charcoal connects to the Kafka brokers. Uses the names librdkafka uses for `security.protocol`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum SecurityProtocol {
+    Plaintext,
+    Ssl,
+    SaslPlaintext,
+    SaslSsl,
+}
+
+impl SecurityProtocol {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SecurityProtocol::Plaintext => "PLAINTEXT",
+            SecurityProtocol::Ssl => "SSL",
+            SecurityProtocol::SaslPlaintext => "SASL_PLAINTEXT",
+            SecurityProtocol::SaslSsl => "SASL_SSL",
+        }
+    }
+    fn uses_sasl(&self) -> bool {
+        matches!(
+            self,
+            SecurityProtocol::SaslPlaintext | SecurityProtocol::SaslSsl
+        )
+    }
+    fn uses_ssl(&self) -> bool {
+        matches!(self, SecurityProtocol::Ssl | SecurityProtocol::SaslSsl)
+    }
+}
+
+impl fmt::Display for SecurityProtocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for SecurityProtocol {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_uppercase().as_str() {
+            "PLAINTEXT" => Ok(SecurityProtocol::Plaintext),
+            "SSL" => Ok(SecurityProtocol::Ssl),
+            "SASL_PLAINTEXT" => Ok(SecurityProtocol::SaslPlaintext),
+            "SASL_SSL" => Ok(SecurityProtocol::SaslSsl),
+            _ => Err(()),
+        }
+    }
+}
+
+/// SASL mechanism used to authenticate with Kafka
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+pub enum SaslMechanism {
+    #[default]
+    #[serde(rename = "PLAIN")]
+    Plain,
+    #[serde(rename = "SCRAM-SHA-256")]
+    ScramSha256,
+    #[serde(rename = "SCRAM-SHA-512")]
+    ScramSha512,
+    /// Tokens are fetched from the [`OAuthTokenProvider`] set on the [`SASLConfig`]
+    #[serde(rename = "OAUTHBEARER")]
+    OAuthBearer,
+}
+
+impl SaslMechanism {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SaslMechanism::Plain => "PLAIN",
+            SaslMechanism::ScramSha256 => "SCRAM-SHA-256",
+            SaslMechanism::ScramSha512 => "SCRAM-SHA-512",
+            SaslMechanism::OAuthBearer => "OAUTHBEARER",
+        }
+    }
+}
+
+impl fmt::Display for SaslMechanism {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for SaslMechanism {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_uppercase().as_str() {
+            "PLAIN" => Ok(SaslMechanism::Plain),
+            "SCRAM-SHA-256" => Ok(SaslMechanism::ScramSha256),
+            "SCRAM-SHA-512" => Ok(SaslMechanism::ScramSha512),
+            "OAUTHBEARER" => Ok(SaslMechanism::OAuthBearer),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Token for the OAUTHBEARER SASL mechanism
+#[derive(Clone, Debug)]
+pub struct OAuthToken {
+    pub token: String,
+    /// Kafka principal the token belongs to
+    pub principal_name: String,
+    pub expires_at: SystemTime,
+}
+
+/// Fetches OAUTHBEARER tokens. Kafka asks for a new token before the current one expires
+pub trait OAuthTokenProvider: Send + Sync {
+    fn token(&self) -> Result<OAuthToken, Box<dyn std::error::Error + Send + Sync>>;
+}
+
+impl<F> OAuthTokenProvider for F
+where
+    F: Fn() -> Result<OAuthToken, Box<dyn std::error::Error + Send + Sync>> + Send + Sync,
+{
+    fn token(&self) -> Result<OAuthToken, Box<dyn std::error::Error + Send + Sync>> {
+        self()
+    }
+}
+
+#[derive(Clone, Deserialize)]
+pub struct SASLConfig {
+    /// Defaults to PLAIN
+    #[serde(default)]
+    pub mechanism: SaslMechanism,
+    /// Kafka Username. Not used by OAUTHBEARER
+    #[serde(default)]
+    pub kafka_username: String,
+    /// Kafka Password. Not used by OAUTHBEARER
+    #[serde(default)]
+    pub kafka_password: String,
+    /// Required for OAUTHBEARER. Can't be loaded from a config file
+    #[serde(skip)]
+    pub oauth_token_provider: Option<Arc<dyn OAuthTokenProvider>>,
+}
+
+/// Named sets of librdkafka settings for common setups.
+/// Properties in `producer_properties` and `consumer_properties` override the preset
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum KafkaPreset {
+    /// Send every message right away and fetch as soon as data is available
+    LowLatency,
+    /// Batch and compress messages and wait for all replicas
+    HighThroughput,
+    /// Single local broker: short timeouts, no compression and topics are created on first use
+    LocalDevelopment,
+}
+
+impl KafkaPreset {
+    pub fn producer_properties(&self) -> &'static [(&'static str, &'static str)] {
+        match self {
+            KafkaPreset::LowLatency => &[
+                ("linger.ms", "0"),
+                ("batch.num.messages", "1"),
+                ("acks", "1"),
+                ("compression.type", "none"),
+            ],
+            KafkaPreset::HighThroughput => &[
+                ("linger.ms", "50"),
+                ("batch.num.messages", "10000"),
+                ("batch.size", "1000000"),
+                ("acks", "all"),
+                ("compression.type", "lz4"),
+            ],
+            KafkaPreset::LocalDevelopment => &[
+                ("linger.ms", "0"),
+                ("acks", "1"),
+                ("compression.type", "none"),
+                ("message.timeout.ms", "5000"),
+            ],
+        }
+    }
+    pub fn consumer_properties(&self) -> &'static [(&'static str, &'static str)] {
+        match self {
+            KafkaPreset::LowLatency => &[("fetch.wait.max.ms", "10"), ("fetch.min.bytes", "1")],
+            KafkaPreset::HighThroughput => {
+                &[("fetch.wait.max.ms", "100"), ("fetch.min.bytes", "65536")]
+            }
+            KafkaPreset::LocalDevelopment => &[
+                ("fetch.wait.max.ms", "10"),
+                ("allow.auto.create.topics", "true"),
+            ],
+        }
+    }
+}
+
+impl FromStr for KafkaPreset {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "low-latency" => Ok(KafkaPreset::LowLatency),
+            "high-throughput" => Ok(KafkaPreset::HighThroughput),
+            "local-development" => Ok(KafkaPreset::LocalDevelopment),
+            _ => Err(()),
+        }
+    }
+}
+
+/// How the Kafka consumer group ID is picked. Every Charcoal instance should have its own group,
+/// instances sharing a group split Hearth's messages between them
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum GroupIdStrategy {
+    /// New group on every start. Offsets are never reused.
+    /// The ID is picked once per config, so clients rebuilt by `Charcoal::update_credentials` stay in the group
+    #[default]
+    Random,
+    Fixed(String),
+    /// `charcoal-{bot_id}-{shard_id}`, stable across restarts of the same shard
+    Bot {
+        bot_id: u64,
+        shard_id: u64,
+    },
+}
+
+impl GroupIdStrategy {
+    pub fn group_id(&self) -> String {
+        match self {
+            GroupIdStrategy::Random => nanoid!(),
+            GroupIdStrategy::Fixed(group_id) => group_id.clone(),
+            GroupIdStrategy::Bot { bot_id, shard_id } => {
+                format!("charcoal-{}-{}", bot_id, shard_id)
+            }
+        }
+    }
+    /// Replace `Random` with a fixed random ID, so every client built from the config joins the same group
+    pub(crate) fn resolve(&mut self) {
+        if *self == GroupIdStrategy::Random {
+            *self = GroupIdStrategy::Fixed(nanoid!());
+        }
+    }
+}
+
+/// Where the consumer starts reading when its group has no committed offset
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum OffsetReset {
+    Earliest,
+    #[default]
+    Latest,
+}
+
+impl OffsetReset {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            OffsetReset::Earliest => "earliest",
+            OffsetReset::Latest => "latest",
+        }
+    }
+}
+
+impl FromStr for OffsetReset {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "earliest" => Ok(OffsetReset::Earliest),
+            "latest" => Ok(OffsetReset::Latest),
+            _ => Err(()),
+        }
+    }
+}
+
+/// What Kafka records sent to Hearth are keyed by. Records with the same key stay in order
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RecordKey {
+    #[default]
+    Guild,
+    /// Job ID for messages to a worker, job creation requests are still keyed by guild ID
+    Job,
+}
+
+#[derive(Clone, Deserialize)]
+/// Configuration for charcoal
+pub struct CharcoalConfig {
+    /// Kafka brokers as host:port. Brokers passed to `init_charcoal` are added to these
+    #[serde(default)]
+    pub brokers: Vec<String>,
+    /// Security protocol to use. If left as None it is picked based on `ssl` and `sasl`,
+    /// with SASL always using SASL_SSL
+    #[serde(default)]
+    pub security_protocol: Option<SecurityProtocol>,
+    /// Configure SSl for kafka. If left as None no SSL is configured
+    pub ssl: Option<SSLConfig>,
+    /// Configure SASL Authentication for Kafka. If left as None no SASL is configured
+    pub sasl: Option<SASLConfig>,
+    /// Kafka topic to connect to. This should be the same one the hearth server(s) are on.
+    pub kafka_topic: String,
+    /// Topic Charcoal sends requests to Hearth on. Defaults to `kafka_topic`
+    #[serde(default)]
+    pub outbound_topic: Option<String>,
+    /// Topic Charcoal receives Hearth's events on. Defaults to `kafka_topic`
+    #[serde(default)]
+    pub inbound_topic: Option<String>,
+    /// If set, messages for a worker are sent to the topic `{worker_topic_prefix}{worker_id}`
+    /// instead of the outbound topic, so only the worker that owns the job receives them
+    #[serde(default)]
+    pub worker_topic_prefix: Option<String>,
+    /// Identifies this Charcoal instance to Hearth when several bots or shards share a topic.
+    /// A random ID is used if left as None
+    #[serde(default)]
+    pub client_id: Option<String>,
+    #[serde(default)]
+    pub record_key: RecordKey,
+    /// Buffering and retries for messages sent to Hearth
+    #[serde(default)]
+    pub outbound: OutboundConfig,
+    /// Records from Hearth that Charcoal can't use are copied to this topic, with the reason in their headers
+    #[serde(default)]
+    pub dead_letter_topic: Option<String>,
+    /// Ask workers for their protocol version and supported actions when connecting.
+    /// Off by default, workers on hearth-interconnect 0.1.0 don't answer with these yet
+    #[serde(default)]
+    pub capability_handshake: bool,
+    #[serde(default)]
+    pub group_id: GroupIdStrategy,
+    #[serde(default)]
+    pub offset_reset: OffsetReset,
+    /// Skip messages older than this many seconds until the consumer has caught up after starting,
+    /// so a restarted bot does not act on old events
+    #[serde(default)]
+    pub skip_stale_after_secs: Option<u64>,
+    /// librdkafka settings applied before `producer_properties` and `consumer_properties`
+    #[serde(default)]
+    pub preset: Option<KafkaPreset>,
+    /// Extra librdkafka properties for the producer. These override Charcoal's own settings
+    #[serde(default)]
+    pub producer_properties: HashMap<String, String>,
+    /// Extra librdkafka properties for the consumer. These override Charcoal's own settings
+    #[serde(default)]
+    pub consumer_properties: HashMap<String, String>,
+}
+
+impl CharcoalConfig {
+    pub fn builder() -> CharcoalConfigBuilder {
+        CharcoalConfigBuilder::default()
+    }
+    /// Load the config from environment variables:
+    /// - `CHARCOAL_BROKERS`: comma separated list of brokers, may be left out if they are passed to `init_charcoal`
+    /// - `CHARCOAL_KAFKA_TOPIC`
+    /// - `CHARCOAL_OUTBOUND_TOPIC`, `CHARCOAL_INBOUND_TOPIC` and `CHARCOAL_WORKER_TOPIC_PREFIX`
+    /// - `CHARCOAL_CLIENT_ID`
+    /// - `CHARCOAL_DEAD_LETTER_TOPIC`
+    /// - `CHARCOAL_CAPABILITY_HANDSHAKE`: true to ask workers for their capabilities
+    /// - `CHARCOAL_SSL_CA`, `CHARCOAL_SSL_CERT` and `CHARCOAL_SSL_KEY` to enable SSL, as paths or PEM data
+    /// - `CHARCOAL_KAFKA_USERNAME` and `CHARCOAL_KAFKA_PASSWORD` to enable SASL
+    /// - `CHARCOAL_SASL_MECHANISM`: PLAIN, SCRAM-SHA-256 or SCRAM-SHA-512
+    /// - `CHARCOAL_SECURITY_PROTOCOL`: PLAINTEXT, SSL, SASL_PLAINTEXT or SASL_SSL
+    /// - `CHARCOAL_KAFKA_PRESET`: low-latency, high-throughput or local-development
+    /// - `CHARCOAL_GROUP_ID`: fixed consumer group ID, a random one is used if unset
+    /// - `CHARCOAL_OFFSET_RESET`: earliest or latest
+    /// - `CHARCOAL_SKIP_STALE_AFTER_SECS`
+    pub fn from_env() -> Result<CharcoalConfig, ConfigError> {
+        let mut builder =
+            CharcoalConfig::builder().kafka_topic(required_env("CHARCOAL_KAFKA_TOPIC")?);
+
+        if let Ok(brokers) = env::var("CHARCOAL_BROKERS") {
+            builder = builder.brokers(split_brokers(&brokers));
+        }
+        if let Some(protocol) = parsed_env("CHARCOAL_SECURITY_PROTOCOL")? {
+            builder = builder.security_protocol(protocol);
+        }
+        if let Ok(topic) = env::var("CHARCOAL_OUTBOUND_TOPIC") {
+            builder = builder.outbound_topic(topic);
+        }
+        if let Ok(topic) = env::var("CHARCOAL_INBOUND_TOPIC") {
+            builder = builder.inbound_topic(topic);
+        }
+        if let Ok(prefix) = env::var("CHARCOAL_WORKER_TOPIC_PREFIX") {
+            builder = builder.worker_topic_prefix(prefix);
+        }
+        if let Ok(client_id) = env::var("CHARCOAL_CLIENT_ID") {
+            builder = builder.client_id(client_id);
+        }
+        if let Ok(topic) = env::var("CHARCOAL_DEAD_LETTER_TOPIC") {
+            builder = builder.dead_letter_topic(topic);
+        }
+        if let Some(enabled) = parsed_env("CHARCOAL_CAPABILITY_HANDSHAKE")? {
+            builder = builder.capability_handshake(enabled);
+        }
+        if let Some(preset) = parsed_env("CHARCOAL_KAFKA_PRESET")? {
+            builder = builder.preset(preset);
+        }
+        if let Ok(group_id) = env::var("CHARCOAL_GROUP_ID") {
+            builder = builder.group_id(GroupIdStrategy::Fixed(group_id));
+        }
+        if let Some(offset_reset) = parsed_env("CHARCOAL_OFFSET_RESET")? {
+            builder = builder.offset_reset(offset_reset);
+        }
+        if let Some(secs) = parsed_env("CHARCOAL_SKIP_STALE_AFTER_SECS")? {
+            builder = builder.skip_stale_after(Duration::from_secs(secs));
+        }
+
+        if env::var_os("CHARCOAL_SSL_CA").is_some() {
+            builder = builder.ssl(
+                PemSource::detect(required_env("CHARCOAL_SSL_CA")?),
+                PemSource::detect(required_env("CHARCOAL_SSL_CERT")?),
+                PemSource::detect(required_env("CHARCOAL_SSL_KEY")?),
+            );
+        }
+        if env::var_os("CHARCOAL_KAFKA_USERNAME").is_some() {
+            builder = builder.sasl_with_mechanism(
+                parsed_env("CHARCOAL_SASL_MECHANISM")?.unwrap_or_default(),
+                required_env("CHARCOAL_KAFKA_USERNAME")?,
+                required_env("CHARCOAL_KAFKA_PASSWORD")?,
+            );
+        }
+        builder.build()
+    }
+    /// Load the config from a TOML file with the same layout as this struct.
+    /// `brokers` may be left out if they are passed to `init_charcoal`
+    pub fn from_toml(path: impl AsRef<Path>) -> Result<CharcoalConfig, ConfigError> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path).context(FailedToReadConfigSnafu { path })?;
+        let mut config: CharcoalConfig =
+            toml::from_str(&text).context(InvalidTomlSnafu { path })?;
+        config.group_id.resolve();
+        config.validate_settings()?;
+        Ok(config)
+    }
+    /// Security protocol that will be used, either the configured one or the one picked based on `ssl` and `sasl`
+    pub fn security_protocol(&self) -> SecurityProtocol {
+        match (self.security_protocol, &self.ssl, &self.sasl) {
+            (Some(protocol), _, _) => protocol,
+            (None, _, Some(_)) => SecurityProtocol::SaslSsl,
+            (None, Some(_), None) => SecurityProtocol::Ssl,
+            (None, None, None) => SecurityProtocol::Plaintext,
+        }
+    }
+    /// Check that at least one broker is configured, the topic name is legal, the SSL certificates are valid
+    /// and the security settings fit together
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        ensure!(!self.brokers.is_empty(), NoBrokersSnafu);
+        self.validate_settings()
+    }
+    /// Everything `validate` checks except that a broker is configured,
+    /// since brokers can still be added by `init_charcoal`
+    fn validate_settings(&self) -> Result<(), ConfigError> {
+        for broker in &self.brokers {
+            let valid = broker
+                .rsplit_once(':')
+                .is_some_and(|(host, port)| !host.is_empty() && port.parse::<u16>().is_ok());
+            ensure!(valid, InvalidBrokerSnafu { broker });
+        }
+
+        for topic in [
+            Some(&self.kafka_topic),
+            self.outbound_topic.as_ref(),
+            self.inbound_topic.as_ref(),
+            self.worker_topic_prefix.as_ref(),
+            self.dead_letter_topic.as_ref(),
+        ]
+        .into_iter()
+        .flatten()
+        {
+            validate_topic(topic)?;
+        }
+        if let Some(topic) = &self.dead_letter_topic {
+            ensure!(
+                topic != self.inbound_topic(),
+                InvalidTopicSnafu {
+                    topic,
+                    reason: "dead-letter topic must differ from the inbound topic",
+                }
+            );
+        }
+
+        if let Some(ssl) = &self.ssl {
+            ssl.validate()?;
+        }
+
+        self.validate_security()
+    }
+    fn validate_security(&self) -> Result<(), ConfigError> {
+        let protocol = self.security_protocol();
+        ensure!(
+            self.ssl.is_none() || protocol.uses_ssl(),
+            IncompatibleSecuritySnafu {
+                protocol,
+                reason: "does not use SSL, but an SSL config was given",
+            }
+        );
+        match &self.sasl {
+            None => ensure!(
+                !protocol.uses_sasl(),
+                IncompatibleSecuritySnafu {
+                    protocol,
+                    reason: "requires a SASL config",
+                }
+            ),
+            Some(sasl) => {
+                ensure!(
+                    protocol.uses_sasl(),
+                    IncompatibleSecuritySnafu {
+                        protocol,
+                        reason: "does not use SASL, but a SASL config was given",
+                    }
+                );
+                let mechanism = sasl.mechanism;
+                if mechanism == SaslMechanism::OAuthBearer {
+                    ensure!(
+                        sasl.oauth_token_provider.is_some(),
+                        MissingOAuthTokenProviderSnafu
+                    );
+                } else {
+                    ensure!(
+                        !sasl.kafka_username.is_empty() && !sasl.kafka_password.is_empty(),
+                        MissingSaslCredentialsSnafu { mechanism }
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+    pub fn outbound_topic(&self) -> &str {
+        self.outbound_topic.as_deref().unwrap_or(&self.kafka_topic)
+    }
+    pub fn inbound_topic(&self) -> &str {
+        self.inbound_topic.as_deref().unwrap_or(&self.kafka_topic)
+    }
+    /// Topic a message is sent on
+    pub fn topic_for(&self, message: &Message) -> Cow<'_, str> {
+        match (&self.worker_topic_prefix, message) {
+            (Some(prefix), Message::DirectWorkerCommunication(d)) => {
+                Cow::Owned(format!("{}{}", prefix, d.worker_id))
+            }
+            _ => Cow::Borrowed(self.outbound_topic()),
+        }
+    }
+    /// Brokers in the format librdkafka expects
+    pub(crate) fn bootstrap_servers(&self) -> String {
+        self.brokers.join(",")
+    }
+}
+
+fn validate_topic(topic: &str) -> Result<(), ConfigError> {
+    let reason = if topic.is_empty() {
+        "must not be empty".to_string()
+    } else if topic.len() > MAX_TOPIC_LENGTH {
+        format!("must be at most {} characters", MAX_TOPIC_LENGTH)
+    } else if topic == "." || topic == ".." {
+        "must not be . or ..".to_string()
+    } else if !topic
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'))
+    {
+        "may only contain ASCII letters, digits, '.', '_' and '-'".to_string()
+    } else {
+        return Ok(());
+    };
+    InvalidTopicSnafu { topic, reason }.fail()
+}
+
+fn required_env(name: &str) -> Result<String, ConfigError> {
+    env::var(name).ok().context(MissingEnvVarSnafu { name })
+}
+
+/// Parse an optional environment variable
+fn parsed_env<T: FromStr>(name: &str) -> Result<Option<T>, ConfigError> {
+    match env::var(name) {
+        Ok(value) => match value.parse() {
+            Ok(parsed) => Ok(Some(parsed)),
+            Err(_) => InvalidEnvVarSnafu { name, value }.fail(),
+        },
+        Err(_) => Ok(None),
+    }
+}
+
+/// Split a comma separated broker list
+pub(crate) fn split_brokers(brokers: &str) -> Vec<String> {
+    brokers
+        .split(',')
+        .map(str::trim)
+        .filter(|b| !b.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// Builds a [`CharcoalConfig`], validating it in `build`
+#[derive(Default)]
+pub struct CharcoalConfigBuilder {
+    brokers: Vec<String>,
+    security_protocol: Option<SecurityProtocol>,
+    ssl: Option<SSLConfig>,
+    sasl: Option<SASLConfig>,
+    kafka_topic: String,
+    outbound_topic: Option<String>,
+    inbound_topic: Option<String>,
+    worker_topic_prefix: Option<String>,
+    client_id: Option<String>,
+    record_key: RecordKey,
+    outbound: OutboundConfig,
+    dead_letter_topic: Option<String>,
+    capability_handshake: bool,
+    group_id: GroupIdStrategy,
+    offset_reset: OffsetReset,
+    skip_stale_after_secs: Option<u64>,
+    preset: Option<KafkaPreset>,
+    producer_properties: HashMap<String, String>,
+    consumer_properties: HashMap<String, String>,
+}
+
+impl CharcoalConfigBuilder {
+    pub fn broker(mut self, broker: impl Into<String>) -> Self {
+        self.brokers.push(broker.into());
+        self
+    }
+    pub fn brokers(mut self, brokers: impl IntoIterator<Item = String>) -> Self {
+        self.brokers.extend(brokers);
+        self
+    }
+    pub fn kafka_topic(mut self, topic: impl Into<String>) -> Self {
+        self.kafka_topic = topic.into();
+        self
+    }
+    pub fn ssl(
+        mut self,
+        ssl_ca: impl Into<PemSource>,
+        ssl_cert: impl Into<PemSource>,
+        ssl_key: impl Into<PemSource>,
+    ) -> Self {
+        self.ssl = Some(SSLConfig {
+            ssl_ca: ssl_ca.into(),
+            ssl_cert: ssl_cert.into(),
+            ssl_key: ssl_key.into(),
+        });
+        self
+    }
+    /// Use SASL with the PLAIN mechanism
+    pub fn sasl(self, username: impl Into<String>, password: impl Into<String>) -> Self {
+        self.sasl_with_mechanism(SaslMechanism::Plain, username, password)
+    }
+    /// Use SASL with a username and password based mechanism
+    pub fn sasl_with_mechanism(
+        mut self,
+        mechanism: SaslMechanism,
+        username: impl Into<String>,
+        password: impl Into<String>,
+    ) -> Self {
+        self.sasl = Some(SASLConfig {
+            mechanism,
+            kafka_username: username.into(),
+            kafka_password: password.into(),
+            oauth_token_provider: None,
+        });
+        self
+    }
+    /// Use SASL with the OAUTHBEARER mechanism
+    pub fn oauth_bearer(mut self, provider: impl OAuthTokenProvider + 'static) -> Self {
+        self.sasl = Some(SASLConfig {
+            mechanism: SaslMechanism::OAuthBearer,
+            kafka_username: String::new(),
+            kafka_password: String::new(),
+            oauth_token_provider: Some(Arc::new(provider)),
+        });
+        self
+    }
+    /// Override the security protocol picked based on the SSL and SASL settings,
+    /// for example to use SASL_PLAINTEXT
+    pub fn security_protocol(mut self, protocol: SecurityProtocol) -> Self {
+        self.security_protocol = Some(protocol);
+        self
+    }
+    /// Send requests to Hearth on a different topic than `kafka_topic`
+    pub fn outbound_topic(mut self, topic: impl Into<String>) -> Self {
+        self.outbound_topic = Some(topic.into());
+        self
+    }
+    /// Receive Hearth's events on a different topic than `kafka_topic`
+    pub fn inbound_topic(mut self, topic: impl Into<String>) -> Self {
+        self.inbound_topic = Some(topic.into());
+        self
+    }
+    /// Send messages for a worker to `{prefix}{worker_id}`
+    pub fn worker_topic_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.worker_topic_prefix = Some(prefix.into());
+        self
+    }
+    pub fn client_id(mut self, client_id: impl Into<String>) -> Self {
+        self.client_id = Some(client_id.into());
+        self
+    }
+    pub fn record_key(mut self, record_key: RecordKey) -> Self {
+        self.record_key = record_key;
+        self
+    }
+    pub fn outbound(mut self, outbound: OutboundConfig) -> Self {
+        self.outbound = outbound;
+        self
+    }
+    /// Copy records Charcoal can't use to a dead-letter topic
+    pub fn dead_letter_topic(mut self, topic: impl Into<String>) -> Self {
+        self.dead_letter_topic = Some(topic.into());
+        self
+    }
+    pub fn group_id(mut self, group_id: GroupIdStrategy) -> Self {
+        self.group_id = group_id;
+        self
+    }
+    /// Ask workers for their protocol version and supported actions when connecting
+    pub fn capability_handshake(mut self, enabled: bool) -> Self {
+        self.capability_handshake = enabled;
+        self
+    }
+    pub fn offset_reset(mut self, offset_reset: OffsetReset) -> Self {
+        self.offset_reset = offset_reset;
+        self
+    }
+    /// Skip messages older than `age` until the consumer has caught up after starting
+    pub fn skip_stale_after(mut self, age: Duration) -> Self {
+        self.skip_stale_after_secs = Some(age.as_secs());
+        self
+    }
+    pub fn preset(mut self, preset: KafkaPreset) -> Self {
+        self.preset = Some(preset);
+        self
+    }
+    /// Set a librdkafka property on the producer
+    pub fn producer_property(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.producer_properties.insert(key.into(), value.into());
+        self
+    }
+    /// Set a librdkafka property on the consumer
+    pub fn consumer_property(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.consumer_properties.insert(key.into(), value.into());
+        self
+    }
+    /// Build and validate the config. Brokers may be left empty if they are passed to `init_charcoal`
+    pub fn build(mut self) -> Result<CharcoalConfig, ConfigError> {
+        self.group_id.resolve();
+        let config = CharcoalConfig {
+            brokers: self.brokers,
+            security_protocol: self.security_protocol,
+            ssl: self.ssl,
+            sasl: self.sasl,
+            kafka_topic: self.kafka_topic,
+            outbound_topic: self.outbound_topic,
+            inbound_topic: self.inbound_topic,
+            worker_topic_prefix: self.worker_topic_prefix,
+            client_id: self.client_id,
+            record_key: self.record_key,
+            outbound: self.outbound,
+            dead_letter_topic: self.dead_letter_topic,
+            capability_handshake: self.capability_handshake,
+            group_id: self.group_id,
+            offset_reset: self.offset_reset,
+            skip_stale_after_secs: self.skip_stale_after_secs,
+            preset: self.preset,
+            producer_properties: self.producer_properties,
+            consumer_properties: self.consumer_properties,
+        };
+        config.validate_settings()?;
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn random_group_id_is_picked_once() {
+        let config = CharcoalConfig::builder()
+            .brokers(vec!["localhost:9092".to_string()])
+            .kafka_topic("charcoal")
+            .build()
+            .unwrap();
+        let GroupIdStrategy::Fixed(group_id) = &config.group_id else {
+            panic!("random group ID was not resolved");
+        };
+        assert_eq!(config.clone().group_id.group_id(), *group_id);
+    }
+
+    fn builder() -> CharcoalConfigBuilder {
+        CharcoalConfig::builder().kafka_topic("charcoal")
+    }
+
+    #[test]
+    fn brokers_are_only_required_by_validate() {
+        let config = builder().build().unwrap();
+        assert!(matches!(config.validate(), Err(ConfigError::NoBrokers)));
+
+        let config = builder().broker("localhost:9092").build().unwrap();
+        assert!(config.validate().is_ok());
+        for broker in ["localhost", ":9092", "localhost:port", "localhost:99999"] {
+            assert!(matches!(
+                builder().broker(broker).build(),
+                Err(ConfigError::InvalidBroker { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn security_combinations() {
+        assert_eq!(
+            builder().build().unwrap().security_protocol(),
+            SecurityProtocol::Plaintext
+        );
+        let sasl = builder().sasl("user", "password").build().unwrap();
+        assert_eq!(sasl.security_protocol(), SecurityProtocol::SaslSsl);
+        assert!(builder()
+            .security_protocol(SecurityProtocol::SaslPlaintext)
+            .sasl("user", "password")
+            .build()
+            .is_ok());
+
+        assert!(matches!(
+            builder()
+                .security_protocol(SecurityProtocol::Ssl)
+                .sasl("user", "password")
+                .build(),
+            Err(ConfigError::IncompatibleSecurity { .. })
+        ));
+        assert!(matches!(
+            builder()
+                .security_protocol(SecurityProtocol::SaslPlaintext)
+                .build(),
+            Err(ConfigError::IncompatibleSecurity { .. })
+        ));
+        assert!(matches!(
+            builder()
+                .sasl_with_mechanism(SaslMechanism::ScramSha256, "user", "")
+                .build(),
+            Err(ConfigError::MissingSaslCredentials { .. })
+        ));
+    }
+
+    #[test]
+    fn topic_checks() {
+        for topic in [
+            "",
+            ".",
+            "..",
+            "has space",
+            "ümlaut",
+            &"a".repeat(MAX_TOPIC_LENGTH + 1),
+        ] {
+            assert!(
+                matches!(validate_topic(topic), Err(ConfigError::InvalidTopic { .. })),
+                "{topic:?} should be rejected"
+            );
+        }
+        assert!(validate_topic("hearth.events_v1-a").is_ok());
+
+        assert!(matches!(
+            builder().outbound_topic("bad topic").build(),
+            Err(ConfigError::InvalidTopic { .. })
+        ));
+        assert!(matches!(
+            builder().dead_letter_topic("charcoal").build(),
+            Err(ConfigError::InvalidTopic { .. })
+        ));
+        assert!(builder()
+            .inbound_topic("events")
+            .dead_letter_topic("charcoal")
+            .build()
+            .is_ok());
+    }
+
+    // The only test that touches CHARCOAL_* variables, so tests running in parallel don't see each other's values
+    #[test]
+    fn env_parsing() {
+        let clear = || {
+            for (name, _) in env::vars().filter(|(name, _)| name.starts_with("CHARCOAL_")) {
+                env::remove_var(name);
+            }
+        };
+        clear();
+        assert!(matches!(
+            CharcoalConfig::from_env(),
+            Err(ConfigError::MissingEnvVar { .. })
+        ));
+
+        env::set_var("CHARCOAL_KAFKA_TOPIC", "charcoal");
+        let config = CharcoalConfig::from_env().unwrap();
+        assert!(config.brokers.is_empty());
+
+        env::set_var("CHARCOAL_BROKERS", "a:9092, b:9092,");
+        env::set_var("CHARCOAL_GROUP_ID", "bots");
+        env::set_var("CHARCOAL_OFFSET_RESET", "Earliest");
+        let config = CharcoalConfig::from_env().unwrap();
+        assert_eq!(config.brokers, ["a:9092", "b:9092"]);
+        assert_eq!(config.group_id, GroupIdStrategy::Fixed("bots".to_string()));
+        assert_eq!(config.offset_reset, OffsetReset::Earliest);
+        assert!(!config.capability_handshake);
+
+        env::set_var("CHARCOAL_CAPABILITY_HANDSHAKE", "true");
+        assert!(CharcoalConfig::from_env().unwrap().capability_handshake);
+
+        env::set_var("CHARCOAL_KAFKA_PRESET", "fastest");
+        assert!(matches!(
+            CharcoalConfig::from_env(),
+            Err(ConfigError::InvalidEnvVar { .. })
+        ));
+        env::remove_var("CHARCOAL_KAFKA_PRESET");
+
+        env::set_var("CHARCOAL_KAFKA_USERNAME", "user");
+        assert!(matches!(
+            CharcoalConfig::from_env(),
+            Err(ConfigError::MissingEnvVar { .. })
+        ));
+        clear();
+    }
+}
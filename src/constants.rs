@@ -1,3 +1,58 @@
+use std::time::Duration;
+
 pub const EXPIRATION_LAGGED_BY_4: f32 = 200.0;
 pub const EXPIRATION_LAGGED_BY_2: f32 = 450.0;
 pub const EXPIRATION_LAGGED_BY_1: f32 = 650.0;
+
+/// How often the queue runner checks whether the current track has ended
+pub const QUEUE_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// How often the duration of a track is requested again while Hearth hasn't reported it
+pub const METADATA_RETRY_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How long a track plays before the queue moves on if Hearth never answers metadata requests for it
+pub const UNKNOWN_DURATION_TIMEOUT: Duration = Duration::from_secs(15 * 60);
+
+/// How many played tracks are kept in each player's history
+pub const HISTORY_LIMIT: usize = 50;
+
+/// Most Kafka records the background thread handles before it sends messages from players again
+pub const MAX_RECORDS_PER_POLL: usize = 64;
+
+/// How long the background thread waits before it sends a handshake again that could not be sent
+pub const HANDSHAKE_RETRY_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Charcoal version, sent to Hearth in the `client-version` header
+pub const CLIENT_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Header with the Charcoal version that sent a record
+pub const CLIENT_VERSION_HEADER: &str = "client-version";
+
+/// Header with the name of the message variant a record contains
+pub const MESSAGE_TYPE_HEADER: &str = "message-type";
+
+/// Header with the request ID of the message a record contains, if it has one
+pub const REQUEST_ID_HEADER: &str = "request-id";
+
+/// Header with the reason a record was copied to the dead-letter topic
+pub const FAILURE_KIND_HEADER: &str = "failure-kind";
+
+/// Header with details about why a dead-lettered record could not be used
+pub const FAILURE_DETAIL_HEADER: &str = "failure-detail";
+
+/// Headers with the topic, partition and offset a dead-lettered record was read from
+pub const SOURCE_TOPIC_HEADER: &str = "source-topic";
+pub const SOURCE_PARTITION_HEADER: &str = "source-partition";
+pub const SOURCE_OFFSET_HEADER: &str = "source-offset";
+
+/// Header that identifies the Charcoal instance a record belongs to
+pub const CLIENT_ID_HEADER: &str = "client-id";
+
+/// Version of the Hearth protocol Charcoal speaks, the version of `hearth-interconnect`
+pub const PROTOCOL_VERSION: &str = "0.1.0";
+
+/// Header with the protocol version of the sender, sent by Charcoal and by workers answering its handshake
+pub const PROTOCOL_VERSION_HEADER: &str = "protocol-version";
+
+/// Header with the comma separated `DWCActionType`s a worker supports, sent in its handshake answer
+pub const SUPPORTED_ACTIONS_HEADER: &str = "supported-actions";
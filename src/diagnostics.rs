@@ -0,0 +1,131 @@
+//! Diagnostics for Kafka records Charcoal could not use.
+//!
+//! Records that fail to parse, have no payload or contain a message Charcoal does not handle are counted,
+//! a few recent ones are kept as samples and an optional [`DiagnosticsHook`] is called for each of them.
+//! This helps to find version mismatches between Charcoal and Hearth
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+
+/// How many recent bad records are kept
+const SAMPLE_LIMIT: usize = 16;
+/// Longest payload kept in a sample, in bytes
+const SAMPLE_PAYLOAD_LIMIT: usize = 1024;
+
+/// Why a record could not be used
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum BadRecordKind {
+    /// Payload is not a message Charcoal understands
+    InvalidPayload,
+    /// Record has no payload
+    EmptyPayload,
+    /// Message parsed, but Charcoal has no handler for it
+    UnhandledMessage,
+}
+
+impl BadRecordKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BadRecordKind::InvalidPayload => "invalid-payload",
+            BadRecordKind::EmptyPayload => "empty-payload",
+            BadRecordKind::UnhandledMessage => "unhandled-message",
+        }
+    }
+}
+
+impl fmt::Display for BadRecordKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// A record Charcoal could not use
+#[derive(Clone, Debug)]
+pub struct BadRecord {
+    pub kind: BadRecordKind,
+    pub topic: String,
+    pub partition: i32,
+    pub offset: i64,
+    /// Parse error or the name of the unhandled message
+    pub detail: String,
+    /// Start of the payload, lossily decoded as UTF-8
+    pub payload: String,
+}
+
+/// Called for every record Charcoal could not use
+pub trait DiagnosticsHook: Send + Sync {
+    fn bad_record(&self, record: &BadRecord);
+}
+
+impl<F> DiagnosticsHook for F
+where
+    F: Fn(&BadRecord) + Send + Sync,
+{
+    fn bad_record(&self, record: &BadRecord) {
+        self(record)
+    }
+}
+
+/// Amount of bad records seen per kind
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BadRecordCounts {
+    pub invalid_payload: u64,
+    pub empty_payload: u64,
+    pub unhandled_message: u64,
+}
+
+#[derive(Default)]
+pub struct Diagnostics {
+    invalid_payload: AtomicU64,
+    empty_payload: AtomicU64,
+    unhandled_message: AtomicU64,
+    samples: Mutex<VecDeque<BadRecord>>,
+    hook: RwLock<Option<Arc<dyn DiagnosticsHook>>>,
+}
+
+impl Diagnostics {
+    pub fn counts(&self) -> BadRecordCounts {
+        BadRecordCounts {
+            invalid_payload: self.invalid_payload.load(Ordering::Relaxed),
+            empty_payload: self.empty_payload.load(Ordering::Relaxed),
+            unhandled_message: self.unhandled_message.load(Ordering::Relaxed),
+        }
+    }
+    /// The most recent bad records, oldest first
+    pub fn samples(&self) -> Vec<BadRecord> {
+        self.samples.lock().unwrap().iter().cloned().collect()
+    }
+    pub fn set_hook(&self, hook: impl DiagnosticsHook + 'static) {
+        *self.hook.write().unwrap() = Some(Arc::new(hook));
+    }
+    pub fn clear_hook(&self) {
+        *self.hook.write().unwrap() = None;
+    }
+    pub(crate) fn record(&self, record: BadRecord) {
+        let counter = match record.kind {
+            BadRecordKind::InvalidPayload => &self.invalid_payload,
+            BadRecordKind::EmptyPayload => &self.empty_payload,
+            BadRecordKind::UnhandledMessage => &self.unhandled_message,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+
+        let hook = self.hook.read().unwrap().clone();
+        if let Some(hook) = hook {
+            hook.bad_record(&record);
+        }
+
+        let mut samples = self.samples.lock().unwrap();
+        if samples.len() >= SAMPLE_LIMIT {
+            samples.pop_front();
+        }
+        samples.push_back(record);
+    }
+}
+
+/// Decode the start of a payload for a sample
+pub(crate) fn sample_payload(payload: &[u8]) -> String {
+    let end = payload.len().min(SAMPLE_PAYLOAD_LIMIT);
+    String::from_utf8_lossy(&payload[..end]).into_owned()
+}
@@ -0,0 +1,101 @@
+//! Strongly typed IDs used throughout Charcoal's public API.
+//! They are converted to the plain strings Hearth expects only when messages are sent
+
+use nanoid::nanoid;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+macro_rules! define_id {
+    ($(#[$meta:meta])* $name:ident) => {
+        $(#[$meta])*
+        #[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+        #[serde(transparent)]
+        pub struct $name(String);
+
+        impl $name {
+            /// Wrap an ID in the string form Hearth uses.
+            /// There is deliberately no `From<String>`, so one kind of ID can't be passed as another by accident
+            pub fn from_raw(id: impl Into<String>) -> Self {
+                $name(id.into())
+            }
+            pub fn as_str(&self) -> &str {
+                &self.0
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str(&self.0)
+            }
+        }
+
+        impl From<&$name> for $name {
+            fn from(id: &$name) -> Self {
+                id.clone()
+            }
+        }
+
+        impl From<$name> for String {
+            fn from(id: $name) -> Self {
+                id.0
+            }
+        }
+    };
+}
+
+macro_rules! impl_from_u64 {
+    ($name:ident) => {
+        impl From<u64> for $name {
+            fn from(id: u64) -> Self {
+                $name(id.to_string())
+            }
+        }
+    };
+}
+
+define_id!(
+    /// ID of a Discord guild. Each guild has at most one PlayerObject
+    GuildId
+);
+define_id!(
+    /// ID of a Discord voice channel
+    VoiceChannelId
+);
+define_id!(
+    /// ID of a Discord user
+    UserId
+);
+define_id!(
+    /// ID of a Discord role
+    RoleId
+);
+define_id!(
+    /// ID of a job running on a Hearth worker
+    JobId
+);
+define_id!(
+    /// ID of a Hearth worker
+    WorkerId
+);
+define_id!(
+    /// ID attached to a request sent to Hearth so responses and errors can be matched to it
+    RequestId
+);
+
+impl_from_u64!(GuildId);
+impl_from_u64!(VoiceChannelId);
+impl_from_u64!(UserId);
+impl_from_u64!(RoleId);
+
+impl RequestId {
+    /// Generate a new random request ID
+    pub fn new() -> Self {
+        RequestId(nanoid!())
+    }
+}
+
+impl Default for RequestId {
+    fn default() -> Self {
+        RequestId::new()
+    }
+}
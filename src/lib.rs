@@ -1,28 +1,63 @@
 //! Charcoal is a client-library for Hearth that makes it easy to use Hearth with Rust.
 //! See Examples in the Github repo [here](https://github.com/Hearth-Industries/Charcoal/tree/main/examples)
 
-use crate::actions::channel_manager::CreateJobError;
-use crate::background::processor::{init_processor, IPCData};
-use crate::constants::{EXPIRATION_LAGGED_BY_1, EXPIRATION_LAGGED_BY_2, EXPIRATION_LAGGED_BY_4};
+use crate::actions::channel_manager::{ChannelManager, ChannelManagerError};
+use crate::actions::player::TrackSource;
+use crate::actions::queue::PlaybackState;
+use crate::background::processor::{init_processor, IPCData, KafkaClients};
+use crate::ids::{GuildId, JobId, UserId, VoiceChannelId, WorkerId};
+use crate::constants::{
+    EXPIRATION_LAGGED_BY_1, EXPIRATION_LAGGED_BY_2, EXPIRATION_LAGGED_BY_4, PROTOCOL_VERSION,
+};
 use hearth_interconnect::messages::Message;
 use lazy_static::lazy_static;
+use nanoid::nanoid;
 use log::{error, info};
+use snafu::prelude::*;
 use rdkafka::producer::FutureProducer;
-use std::collections::HashMap;
+use dashmap::DashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::sync::Arc;
 use std::time::Duration;
 use tokio::sync::broadcast::error::TryRecvError;
 use tokio::sync::broadcast::{Receiver, Sender};
-use tokio::sync::{broadcast, Mutex, RwLock};
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::{broadcast, mpsc, watch, Mutex, RwLock};
+use tokio::task::JoinHandle;
 use tokio::time;
 
 pub mod actions;
 pub mod background;
+pub mod capabilities;
+pub mod config;
 pub(crate) mod constants;
+pub mod diagnostics;
 mod helpers;
+pub mod ids;
+pub mod now_playing;
+pub mod permissions;
+pub mod playlist;
+#[cfg(feature = "serenity")]
 pub mod serenity;
+pub mod session;
+#[cfg(feature = "twilight")]
+pub mod twilight;
+pub mod voice_state;
+pub mod votes;
 
 use crate::background::connector::{initialize_client, initialize_producer};
+pub use crate::background::connector::InitError;
+pub use crate::background::outbox::{ConnectionState, OutboundConfig};
+use crate::background::connector::InvalidConfigSnafu;
+use crate::config::split_brokers;
+pub use crate::config::{
+    CharcoalConfig, ConfigError, GroupIdStrategy, KafkaPreset, OAuthToken, OAuthTokenProvider,
+    OffsetReset, PemSource, RecordKey, SASLConfig, SSLConfig, SaslMechanism, SecurityProtocol,
+};
+use crate::permissions::{Actor, PermissionDenied, PermissionPolicy, PlayerAction};
+use crate::diagnostics::Diagnostics;
+use crate::capabilities::{Capabilities, Health};
+use crate::votes::Vote;
 use rdkafka::consumer::StreamConsumer;
 
 lazy_static! {
@@ -33,43 +68,261 @@ lazy_static! {
 }
 
 /// Represents an instance in a voice channel
+#[derive(Clone)]
 pub struct PlayerObject {
-    worker_id: Arc<RwLock<Option<String>>>,
-    job_id: Arc<RwLock<Option<String>>>,
-    guild_id: String,
+    worker_id: Arc<RwLock<Option<WorkerId>>>,
+    job_id: Arc<RwLock<Option<JobId>>>,
+    guild_id: GuildId,
     tx: Arc<Sender<IPCData>>,
     bg_com_tx: Sender<IPCData>,
+    queue: Arc<RwLock<VecDeque<TrackSource>>>,
+    playback: Arc<RwLock<PlaybackState>>,
+    tasks: Arc<std::sync::Mutex<Vec<JoinHandle<()>>>>,
+    voice_channel_id: Arc<RwLock<Option<VoiceChannelId>>>,
+    listeners: Arc<RwLock<HashSet<UserId>>>,
+    permission_policy: SharedPolicy,
+    votes: Arc<Mutex<HashMap<PlayerAction, Vote>>>,
+    capabilities: Arc<Capabilities>,
 }
 
+/// Permission policy shared between Charcoal and all of its players
+type SharedPolicy = Arc<std::sync::RwLock<Option<Arc<dyn PermissionPolicy>>>>;
+
+/// Cheap cloneable handle to a PlayerObject stored in Charcoal
+pub type PlayerHandle = PlayerObject;
+
 impl PlayerObject {
     /// Creates a new Player Object that can then be joined to channel and used to playback audio
-    pub async fn new(guild_id: String, com_tx: Sender<IPCData>) -> Result<Self, CreateJobError> {
+    pub(crate) fn new(
+        guild_id: GuildId,
+        com_tx: Sender<IPCData>,
+        permission_policy: SharedPolicy,
+        capabilities: Arc<Capabilities>,
+    ) -> Self {
         let (tx, _rx) = broadcast::channel(16);
 
-        let handler = PlayerObject {
+        PlayerObject {
             worker_id: Arc::new(RwLock::new(None)),
             job_id: Arc::new(RwLock::new(None)),
             guild_id,
             tx: Arc::new(tx),
             bg_com_tx: com_tx,
-        };
-
-        Ok(handler)
+            queue: Arc::new(RwLock::new(VecDeque::new())),
+            playback: Arc::new(RwLock::new(PlaybackState::new())),
+            tasks: Arc::new(std::sync::Mutex::new(vec![])),
+            voice_channel_id: Arc::new(RwLock::new(None)),
+            listeners: Arc::new(RwLock::new(HashSet::new())),
+            permission_policy,
+            votes: Arc::new(Mutex::new(HashMap::new())),
+            capabilities,
+        }
+    }
+    /// ID of the guild this PlayerObject belongs to
+    pub fn guild_id(&self) -> &GuildId {
+        &self.guild_id
+    }
+    /// Voice channel this PlayerObject is connected to
+    pub async fn voice_channel_id(&self) -> Option<VoiceChannelId> {
+        self.voice_channel_id.read().await.clone()
+    }
+    /// Users other than the bot in this PlayerObject's voice channel.
+    /// This is only kept up to date if voice state updates are passed to Charcoal, see the `voice_state` module
+    pub async fn listeners(&self) -> Vec<UserId> {
+        self.listeners.read().await.iter().cloned().collect()
+    }
+    /// Job and worker this PlayerObject is running on. None until a job was created with `join_channel`
+    pub(crate) async fn job(&self) -> Option<(JobId, WorkerId)> {
+        let job_id = self.job_id.read().await.clone()?;
+        let worker_id = self.worker_id.read().await.clone()?;
+        Some((job_id, worker_id))
+    }
+    /// Keep track of a background task so it can be stopped once this PlayerObject is removed
+    pub(crate) fn track_task(&self, task: JoinHandle<()>) {
+        let mut tasks = self.tasks.lock().unwrap();
+        tasks.retain(|t| !t.is_finished());
+        tasks.push(task);
+    }
+    /// Stop all background tasks belonging to this PlayerObject
+    fn shutdown(&self) {
+        for task in self.tasks.lock().unwrap().drain(..) {
+            task.abort();
+        }
     }
 }
 
-/// Stores Charcoal instance
+/// Stores Charcoal instance.
+/// Cloning is cheap and every clone refers to the same players and background thread
+#[derive(Clone)]
 pub struct Charcoal {
-    pub players: Arc<RwLock<HashMap<String, PlayerObject>>>, // Guild ID to PlayerObject
+    players: Arc<DashMap<GuildId, PlayerObject>>,
     pub tx: Sender<IPCData>,
-    pub rx: Receiver<IPCData>,
+    permission_policy: SharedPolicy,
+    /// Config the background thread is currently using
+    config: Arc<Mutex<CharcoalConfig>>,
+    reconnect_tx: UnboundedSender<KafkaClients>,
+    connection_state: watch::Receiver<ConnectionState>,
+    diagnostics: Arc<Diagnostics>,
+    capabilities: Arc<Capabilities>,
+}
+
+/// Result of `Charcoal::remove_player`
+pub struct RemovedPlayer {
+    /// The removed player, None if the guild had no player
+    pub player: Option<PlayerHandle>,
+    /// Why leaving the voice channel failed. The player was removed anyway
+    pub leave_error: Option<ChannelManagerError>,
+}
+
+/// Remove a player from the registry and clean up its background tasks and its route in the background thread
+fn unregister_player(
+    players: &DashMap<GuildId, PlayerObject>,
+    tx: &Sender<IPCData>,
+    guild_id: &GuildId,
+) -> Option<PlayerHandle> {
+    let (_, player) = players.remove(guild_id)?;
+    player.shutdown();
+    if let Err(e) = tx.send(IPCData::RemoveRoute(guild_id.clone())) {
+        error!("Failed to remove route for player with error: {}", e);
+    }
+    Some(player)
 }
 
 impl Charcoal {
-    fn start_global_checker(&mut self) {
+    /// Get the player for a guild, creating it if it does not exist yet.
+    /// Newly created players still need to join a channel with `join_channel`
+    pub fn get_or_create_player(&self, guild_id: impl Into<GuildId>) -> PlayerHandle {
+        let guild_id = guild_id.into();
+        self.players
+            .entry(guild_id.clone())
+            .or_insert_with(|| {
+                PlayerObject::new(
+                    guild_id,
+                    self.tx.clone(),
+                    self.permission_policy.clone(),
+                    self.capabilities.clone(),
+                )
+            })
+            .clone()
+    }
+    /// Get the player for a guild
+    pub fn player(&self, guild_id: impl Into<GuildId>) -> Option<PlayerHandle> {
+        self.players
+            .get(&guild_id.into())
+            .map(|player| player.clone())
+    }
+    /// Get a snapshot of all players
+    pub fn players(&self) -> Vec<PlayerHandle> {
+        self.players
+            .iter()
+            .map(|player| player.value().clone())
+            .collect()
+    }
+    /// Set the policy checked for all players by actions performed through `PlayerObject::acting_for`,
+    /// and by `PlayerObject::authorize` and `Charcoal::authorize`. See the `permissions` module
+    pub fn set_permission_policy(&self, policy: impl PermissionPolicy + 'static) {
+        *self.permission_policy.write().unwrap() = Some(Arc::new(policy));
+    }
+    /// Check whether a user may perform an action in a guild, for example before creating its player.
+    /// Uses the guild's player if there is one, otherwise the policy sees a new player that is not registered
+    pub async fn authorize(
+        &self,
+        guild_id: impl Into<GuildId>,
+        actor: &Actor,
+        action: PlayerAction,
+    ) -> Result<(), PermissionDenied> {
+        let guild_id = guild_id.into();
+        let player = self.player(&guild_id).unwrap_or_else(|| {
+            PlayerObject::new(
+                guild_id,
+                self.tx.clone(),
+                self.permission_policy.clone(),
+                self.capabilities.clone(),
+            )
+        });
+        player.authorize(actor, action).await
+    }
+    /// Remove the permission policy so every action is allowed again
+    pub fn clear_permission_policy(&self) {
+        *self.permission_policy.write().unwrap() = None;
+    }
+    /// Whether Kafka can currently be reached
+    pub fn connection_state(&self) -> ConnectionState {
+        *self.connection_state.borrow()
+    }
+    /// Receiver that is notified when the connection state changes,
+    /// for example to tell users that the audio backend is down
+    pub fn connection_events(&self) -> watch::Receiver<ConnectionState> {
+        self.connection_state.clone()
+    }
+    /// Counters and samples of records from Kafka that Charcoal could not use
+    pub fn diagnostics(&self) -> &Diagnostics {
+        &self.diagnostics
+    }
+    /// Connection state and the protocol versions and supported actions of the workers that answered
+    /// Charcoal's handshake
+    pub fn health(&self) -> Health {
+        Health {
+            connection: self.connection_state(),
+            protocol_version: PROTOCOL_VERSION,
+            workers: self.capabilities.workers(),
+        }
+    }
+    /// Ask workers for their capabilities again, for example after Hearth was upgraded.
+    /// Answers show up in `health` once they arrive.
+    /// Does nothing unless `CharcoalConfig::capability_handshake` is set
+    pub fn refresh_capabilities(&self) {
+        if let Err(e) = self.tx.send(IPCData::RequestCapabilities) {
+            error!("Failed to request worker capabilities with error: {}", e);
+        }
+    }
+    /// Replace the SSL and SASL settings, for example to rotate certificates or passwords.
+    /// New Kafka clients are created and swapped in by the background thread, players are kept.
+    /// If the new settings are invalid or the clients can't be created the old ones stay in use
+    pub async fn update_credentials(
+        &self,
+        ssl: Option<SSLConfig>,
+        sasl: Option<SASLConfig>,
+    ) -> Result<(), InitError> {
+        let mut current = self.config.lock().await;
+        let mut config = current.clone();
+        config.ssl = ssl;
+        config.sasl = sasl;
+        config.validate().context(InvalidConfigSnafu)?;
+
+        let clients = KafkaClients {
+            consumer: initialize_client(&config).await?,
+            producer: initialize_producer(&config)?,
+            config: config.clone(),
+        };
+        self.reconnect_tx
+            .send(clients)
+            .map_err(|_| InitError::BackgroundThreadStopped)?;
+        *current = config;
+        Ok(())
+    }
+    /// Remove the player for a guild, stopping its background tasks.
+    /// If `leave` is true the player leaves its voice channel first. The player is removed even if leaving fails
+    pub async fn remove_player(&self, guild_id: impl Into<GuildId>, leave: bool) -> RemovedPlayer {
+        let guild_id = guild_id.into();
+        let mut leave_error = None;
+        if leave {
+            if let Some(player) = self.player(&guild_id) {
+                // A player without a job has no channel to leave
+                if player.job().await.is_some() {
+                    leave_error = player.exit_channel().await.err();
+                }
+            }
+        }
+        RemovedPlayer {
+            player: unregister_player(&self.players, &self.tx, &guild_id),
+            leave_error,
+        }
+    }
+    fn start_global_checker(&self) {
         info!("Started global data checker!");
         let mut rxx = self.tx.subscribe();
         let t_players = self.players.clone();
+        let t_tx = self.tx.clone();
         let mut tick_adjustments = 0;
         tokio::task::spawn(async move {
             let mut interval = time::interval(Duration::from_secs(1));
@@ -83,14 +336,12 @@ impl Charcoal {
                             match bg.message {
                                 Message::ExternalJobExpired(je) => {
                                     info!("Job Expired: {}", je.job_id);
-                                    let mut t_p_write = t_players.write().await;
-                                    t_p_write.remove(&je.guild_id);
+                                    unregister_player(&t_players, &t_tx, &GuildId::from_raw(je.guild_id));
                                 }
                                 Message::WorkerShutdownAlert(shutdown_alert) => {
                                     info!("Worker shutdown! Cancelling Players!");
-                                    let mut t_p_write = t_players.write().await;
-                                    for job_id in shutdown_alert.affected_guild_ids {
-                                        t_p_write.remove(&job_id);
+                                    for guild_id in shutdown_alert.affected_guild_ids {
+                                        unregister_player(&t_players, &t_tx, &GuildId::from_raw(guild_id));
                                     }
                                 }
                                 _ => {}
@@ -136,60 +387,62 @@ impl Charcoal {
     }
 }
 
-#[derive(Clone)]
-/// Stores SSL Config for Kafka
-pub struct SSLConfig {
-    /// Path to the SSL key file
-    pub ssl_key: String,
-    /// Path to the SSL CA file
-    pub ssl_ca: String,
-    /// Path to the SSL cert file
-    pub ssl_cert: String,
-}
-
-#[derive(Clone)]
-pub struct SASLConfig {
-    /// Kafka Username
-    pub kafka_username: String,
-    /// Kafka Password
-    pub kafka_password: String,
-}
-
-#[derive(Clone)]
-/// Configuration for charcoal
-pub struct CharcoalConfig {
-    /// Configure SSl for kafka. If left as None no SSL is configured
-    pub ssl: Option<SSLConfig>,
-    /// Configure SASL/Password and Username Based Authentication for Kafka. If left as None no SASL is configured
-    pub sasl: Option<SASLConfig>,
-    /// Kafka topic to connect to. This should be the same one the hearth server(s) are on.
-    pub kafka_topic: String,
-}
-
-/// Initializes Charcoal Instance
-pub async fn init_charcoal(broker: String, config: CharcoalConfig) -> Arc<Mutex<Charcoal>> {
-    // This isn't great we should really switch to rdkafka instead of kafka
+/// Initializes Charcoal Instance.
+/// `broker` is a comma separated list of brokers that is added to the brokers in `config`, it may be empty
+pub async fn init_charcoal(
+    broker: String,
+    mut config: CharcoalConfig,
+) -> Result<Charcoal, InitError> {
+    config.brokers.extend(split_brokers(&broker));
+    config.client_id.get_or_insert_with(|| nanoid!());
+    // Configs written as struct literals still have a random group ID to pick
+    config.group_id.resolve();
+    config.validate().context(InvalidConfigSnafu)?;
 
-    let consumer = initialize_client(&broker, &config).await;
+    let consumer = initialize_client(&config).await?;
 
-    let producer = initialize_producer(&broker, &config);
+    let producer = initialize_producer(&config)?;
 
     let (tx, rx) = broadcast::channel(16);
+    let (reconnect_tx, reconnect_rx) = mpsc::unbounded_channel();
+    let (state_tx, state_rx) = watch::channel(ConnectionState::Connected);
 
-    let global_rx = tx.subscribe();
     let sub_tx = tx.clone();
+    let processor_config = config.clone();
+    let diagnostics = Arc::new(Diagnostics::default());
+    let processor_diagnostics = diagnostics.clone();
+    let capabilities = Arc::new(Capabilities::default());
+    let processor_capabilities = capabilities.clone();
 
     tokio::task::spawn(async move {
-        init_processor(rx, sub_tx, consumer, producer, config).await;
+        init_processor(
+            rx,
+            sub_tx,
+            reconnect_rx,
+            state_tx,
+            KafkaClients {
+                consumer,
+                producer,
+                config: processor_config,
+            },
+            processor_diagnostics,
+            processor_capabilities,
+        )
+        .await;
     });
 
-    let mut c_instance = Charcoal {
-        players: Arc::new(RwLock::new(HashMap::new())),
+    let c_instance = Charcoal {
+        players: Arc::new(DashMap::new()),
         tx,
-        rx: global_rx,
+        permission_policy: Arc::new(std::sync::RwLock::new(None)),
+        config: Arc::new(Mutex::new(config)),
+        reconnect_tx,
+        connection_state: state_rx,
+        diagnostics,
+        capabilities,
     };
 
     c_instance.start_global_checker(); // Start checking for expired jobs
 
-    Arc::new(Mutex::new(c_instance))
+    Ok(c_instance)
 }
@@ -0,0 +1,221 @@
+//! Renders "now playing" messages from a player's state and [`Metadata`] reported by Hearth.
+//!
+//! Templates are plain strings with placeholders that are replaced when rendering:
+//! `{title}`, `{artist}`, `{url}`, `{position}`, `{duration}`, `{bar}`, `{volume}`, `{loop}`, `{queue}` and `{next}`
+
+use crate::actions::player::TrackSource;
+use crate::actions::queue::{LoopMode, QueueManager};
+use crate::PlayerObject;
+use hearth_interconnect::messages::Metadata;
+use std::time::Duration;
+
+/// Snapshot of everything shown in a now playing message
+#[derive(Clone, Debug)]
+pub struct NowPlaying {
+    pub source: Option<TrackSource>,
+    pub position: Duration,
+    pub duration: Option<Duration>,
+    /// Volume where 1.0 is 100%
+    pub volume: f32,
+    pub loop_mode: LoopMode,
+    pub queue: Vec<TrackSource>,
+}
+
+impl NowPlaying {
+    /// Take a snapshot of the player's local state
+    pub async fn from_player(player: &PlayerObject) -> NowPlaying {
+        let source = player.now_playing().await;
+        NowPlaying {
+            duration: source.as_ref().and_then(|s| s.duration),
+            source,
+            position: player.position().await,
+            volume: player.volume().await,
+            loop_mode: player.loop_mode().await,
+            queue: player.queued_tracks().await,
+        }
+    }
+    /// Take a snapshot of the player's local state, preferring the position and duration reported by Hearth
+    pub async fn from_metadata(player: &PlayerObject, metadata: &Metadata) -> NowPlaying {
+        let mut now_playing = NowPlaying::from_player(player).await;
+        if let Some(position) = metadata.position {
+            now_playing.position = Duration::from_secs(position);
+        }
+        if let Some(duration) = metadata.duration {
+            now_playing.duration = Some(Duration::from_secs(duration));
+        }
+        now_playing
+    }
+    /// Fraction of the track that has been played, if the duration is known
+    pub fn progress(&self) -> Option<f64> {
+        let duration = self.duration.filter(|d| !d.is_zero())?;
+        Some((self.position.as_secs_f64() / duration.as_secs_f64()).clamp(0.0, 1.0))
+    }
+    /// Render a template, replacing its placeholders with the values of this snapshot
+    pub fn render(&self, template: &str, bar: &ProgressBar) -> String {
+        let (title, artist, url) = match &self.source {
+            Some(source) => (
+                track_name(source).to_string(),
+                source.artist.clone().unwrap_or_default(),
+                source.url.clone(),
+            ),
+            None => (
+                "Nothing is playing".to_string(),
+                String::new(),
+                String::new(),
+            ),
+        };
+        let duration = self
+            .duration
+            .map(format_duration)
+            .unwrap_or_else(|| "?".to_string());
+        let loop_mode = match self.loop_mode {
+            LoopMode::Off => "off".to_string(),
+            LoopMode::Forever => "forever".to_string(),
+            LoopMode::Times(times) => format!("{} more times", times),
+        };
+        let queue = match self.queue.len() {
+            0 => "empty".to_string(),
+            1 => "1 track".to_string(),
+            n => format!("{} tracks", n),
+        };
+        let next = self
+            .queue
+            .first()
+            .map(|s| track_name(s).to_string())
+            .unwrap_or_else(|| "nothing".to_string());
+
+        // Substitute in a single pass so placeholders inside titles and URLs are left alone
+        let mut rendered = String::with_capacity(template.len());
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            rendered.push_str(&rest[..start]);
+            rest = &rest[start..];
+            let placeholder = rest.find('}').map(|end| &rest[1..end]);
+            let value = match placeholder {
+                Some("title") => title.clone(),
+                Some("artist") => artist.clone(),
+                Some("url") => url.clone(),
+                Some("position") => format_duration(self.position),
+                Some("duration") => duration.clone(),
+                Some("bar") => bar.render(self.progress()),
+                Some("volume") => format!("{}%", (self.volume * 100.0).round()),
+                Some("loop") => loop_mode.clone(),
+                Some("queue") => queue.clone(),
+                Some("next") => next.clone(),
+                // Not a placeholder, keep the brace as it is
+                _ => {
+                    rendered.push('{');
+                    rest = &rest[1..];
+                    continue;
+                }
+            };
+            rendered.push_str(&value);
+            rest = &rest[placeholder.map_or(0, str::len) + 2..];
+        }
+        rendered.push_str(rest);
+        rendered
+    }
+    /// Render as plain text
+    pub fn to_text(&self) -> String {
+        self.render(NowPlayingTemplate::TEXT, &ProgressBar::default())
+    }
+    /// Render as Discord markdown
+    pub fn to_markdown(&self) -> String {
+        self.render(NowPlayingTemplate::MARKDOWN, &ProgressBar::default())
+    }
+}
+
+/// Built-in templates
+pub struct NowPlayingTemplate;
+
+impl NowPlayingTemplate {
+    pub const TEXT: &'static str = "Now playing: {title}\n\
+        {bar} {position} / {duration}\n\
+        Volume: {volume} | Loop: {loop} | Queue: {queue}";
+    pub const MARKDOWN: &'static str = "**Now playing:** [{title}]({url})\n\
+        `{bar}` {position} / {duration}\n\
+        Volume: **{volume}** | Loop: **{loop}** | Queue: **{queue}**, up next: {next}";
+}
+
+/// Textual progress bar such as `▬▬▬▬🔘▬▬▬▬▬`
+#[derive(Clone, Debug)]
+pub struct ProgressBar {
+    /// Amount of segments in the bar
+    pub width: usize,
+    pub played: String,
+    pub remaining: String,
+    /// Marks the current position. Leave empty to only use `played` and `remaining`
+    pub head: String,
+}
+
+impl Default for ProgressBar {
+    fn default() -> Self {
+        ProgressBar {
+            width: 20,
+            played: "▬".to_string(),
+            remaining: "▬".to_string(),
+            head: "🔘".to_string(),
+        }
+    }
+}
+
+impl ProgressBar {
+    /// Render the bar for the given progress. An unknown progress renders an empty bar
+    pub fn render(&self, progress: Option<f64>) -> String {
+        let segments = if self.head.is_empty() {
+            self.width
+        } else {
+            self.width.saturating_sub(1)
+        };
+        let played = (progress.unwrap_or(0.0) * segments as f64).round() as usize;
+        let played = played.min(segments);
+        format!(
+            "{}{}{}",
+            self.played.repeat(played),
+            self.head,
+            self.remaining.repeat(segments - played)
+        )
+    }
+}
+
+/// Title of a track, or its URL if the title is unknown
+pub fn track_name(source: &TrackSource) -> &str {
+    source.title.as_deref().unwrap_or(&source.url)
+}
+
+/// Formats a duration as m:ss, or h:mm:ss for durations of an hour or longer
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    let (hours, minutes, seconds) = (secs / 3600, secs / 60 % 60, secs % 60);
+    if hours > 0 {
+        format!("{}:{:02}:{:02}", hours, minutes, seconds)
+    } else {
+        format!("{}:{:02}", minutes, seconds)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn placeholders_in_values_are_not_expanded() {
+        let mut source = TrackSource::http("https://example.com/{volume}".to_string());
+        source.title = Some("{url} {queue}".to_string());
+        let now_playing = NowPlaying {
+            source: Some(source),
+            position: Duration::from_secs(61),
+            duration: None,
+            volume: 0.5,
+            loop_mode: LoopMode::Off,
+            queue: vec![],
+        };
+        assert_eq!(
+            now_playing.render(
+                "{title} | {url} | {volume} | {position}/{duration} | {unknown} {",
+                &ProgressBar::default()
+            ),
+            "{url} {queue} | https://example.com/{volume} | 50% | 1:01/? | {unknown} {"
+        );
+    }
+}
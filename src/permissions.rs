@@ -0,0 +1,484 @@
+//! Optional authorization layer for player actions.
+//!
+//! Set a [`PermissionPolicy`] with `Charcoal::set_permission_policy`, then perform actions on behalf of a
+//! user through [`PlayerObject::acting_for`]. The returned [`ActingPlayer`] implements the same action traits
+//! as `PlayerObject` and checks the policy before anything is sent to Hearth, failing with a `Denied` error.
+//! [`PlayerObject::authorize`] (or `Charcoal::authorize` for guilds without a player) checks an action
+//! without performing it. Without a policy every action is allowed.
+//!
+//! Actions called on `PlayerObject` itself are performed for the bot, for example when the queue moves on
+//! or a vote passed, and are not checked
+
+use crate::actions::channel_manager::{ChannelManager, ChannelManagerError, CreateJobError};
+use crate::actions::history::{HistoryEntry, HistoryManager};
+use crate::actions::player::{Player, PlayerActionError, TrackSource};
+use crate::actions::queue::{LoopMode, QueueManager};
+use crate::actions::track_manager::{TrackActionError, TrackManager};
+use crate::ids::{RoleId, UserId, VoiceChannelId};
+use crate::playlist::{EnqueuePlaylistError, Playlist};
+use crate::PlayerObject;
+use async_trait::async_trait;
+use snafu::prelude::*;
+use std::collections::HashSet;
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Actions that can be restricted by a [`PermissionPolicy`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum PlayerAction {
+    Join,
+    Leave,
+    Play,
+    Skip,
+    Previous,
+    Pause,
+    Resume,
+    Seek,
+    Volume,
+    Loop,
+    ClearQueue,
+    Stop,
+    /// Looking at the queue or the current track
+    View,
+}
+
+impl fmt::Display for PlayerAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            PlayerAction::Join => "join",
+            PlayerAction::Leave => "leave",
+            PlayerAction::Play => "play",
+            PlayerAction::Skip => "skip",
+            PlayerAction::Previous => "go back",
+            PlayerAction::Pause => "pause",
+            PlayerAction::Resume => "resume",
+            PlayerAction::Seek => "seek",
+            PlayerAction::Volume => "change the volume",
+            PlayerAction::Loop => "loop",
+            PlayerAction::ClearQueue => "clear the queue",
+            PlayerAction::Stop => "stop",
+            PlayerAction::View => "view the player",
+        };
+        f.write_str(name)
+    }
+}
+
+/// The user an action is performed for
+#[derive(Clone, Debug)]
+pub struct Actor {
+    pub user_id: UserId,
+    pub role_ids: Vec<RoleId>,
+    /// Voice channel the user is in
+    pub voice_channel_id: Option<VoiceChannelId>,
+}
+
+/// Reason an action was refused. The messages are meant to be shown to users
+#[derive(Debug, Snafu)]
+pub enum PermissionDenied {
+    #[snafu(display("You need the DJ role to {action}"))]
+    MissingDjRole { action: PlayerAction },
+    #[snafu(display("Only the user that requested this track can {action}"))]
+    NotRequester { action: PlayerAction },
+    #[snafu(display("You must be in the same voice channel to {action}"))]
+    NotInSameChannel { action: PlayerAction },
+    #[snafu(display("A vote is required to {action}"))]
+    VoteRequired { action: PlayerAction },
+    #[snafu(display("You can't {action}: {reason}"))]
+    Custom {
+        action: PlayerAction,
+        reason: String,
+    },
+}
+
+#[async_trait]
+/// Decides whether a user may perform an action on a player
+pub trait PermissionPolicy: Send + Sync {
+    async fn check(
+        &self,
+        player: &PlayerObject,
+        actor: &Actor,
+        action: PlayerAction,
+    ) -> Result<(), PermissionDenied>;
+}
+
+/// Restricts actions to users with one of the DJ roles
+pub struct DjRole {
+    pub role_ids: HashSet<RoleId>,
+    pub actions: HashSet<PlayerAction>,
+}
+
+impl DjRole {
+    pub fn new(
+        role_id: impl Into<RoleId>,
+        actions: impl IntoIterator<Item = PlayerAction>,
+    ) -> Self {
+        DjRole {
+            role_ids: HashSet::from([role_id.into()]),
+            actions: actions.into_iter().collect(),
+        }
+    }
+}
+
+#[async_trait]
+impl PermissionPolicy for DjRole {
+    async fn check(
+        &self,
+        _player: &PlayerObject,
+        actor: &Actor,
+        action: PlayerAction,
+    ) -> Result<(), PermissionDenied> {
+        ensure!(
+            !self.actions.contains(&action)
+                || actor.role_ids.iter().any(|r| self.role_ids.contains(r)),
+            MissingDjRoleSnafu { action }
+        );
+        Ok(())
+    }
+}
+
+/// Restricts actions on the current track to the user that requested it.
+/// Tracks without a known requester are not restricted
+pub struct Requester {
+    pub actions: HashSet<PlayerAction>,
+}
+
+impl Requester {
+    pub fn new(actions: impl IntoIterator<Item = PlayerAction>) -> Self {
+        Requester {
+            actions: actions.into_iter().collect(),
+        }
+    }
+}
+
+#[async_trait]
+impl PermissionPolicy for Requester {
+    async fn check(
+        &self,
+        player: &PlayerObject,
+        actor: &Actor,
+        action: PlayerAction,
+    ) -> Result<(), PermissionDenied> {
+        if !self.actions.contains(&action) {
+            return Ok(());
+        }
+        if let Some(requester) = player.now_playing().await.and_then(|s| s.requester) {
+            ensure!(requester == actor.user_id, NotRequesterSnafu { action });
+        }
+        Ok(())
+    }
+}
+
+/// Only allows users in the player's voice channel to control it.
+/// Joining is always allowed so users can bring the bot to their channel
+pub struct SameChannel;
+
+#[async_trait]
+impl PermissionPolicy for SameChannel {
+    async fn check(
+        &self,
+        player: &PlayerObject,
+        actor: &Actor,
+        action: PlayerAction,
+    ) -> Result<(), PermissionDenied> {
+        if action == PlayerAction::Join {
+            return Ok(());
+        }
+        let channel = player.voice_channel_id().await;
+        ensure!(
+            channel.is_none() || channel == actor.voice_channel_id,
+            NotInSameChannelSnafu { action }
+        );
+        Ok(())
+    }
+}
+
+/// Requires a vote for the given actions unless the user is the only listener.
+/// Start a vote with `PlayerObject::vote` when this policy denies an action
+pub struct RequireVote {
+    pub actions: HashSet<PlayerAction>,
+}
+
+impl RequireVote {
+    pub fn new(actions: impl IntoIterator<Item = PlayerAction>) -> Self {
+        RequireVote {
+            actions: actions.into_iter().collect(),
+        }
+    }
+}
+
+#[async_trait]
+impl PermissionPolicy for RequireVote {
+    async fn check(
+        &self,
+        player: &PlayerObject,
+        actor: &Actor,
+        action: PlayerAction,
+    ) -> Result<(), PermissionDenied> {
+        if !self.actions.contains(&action) {
+            return Ok(());
+        }
+        let listeners = player.listeners().await;
+        ensure!(
+            listeners.iter().all(|l| *l == actor.user_id),
+            VoteRequiredSnafu { action }
+        );
+        Ok(())
+    }
+}
+
+/// Allows an action only if every policy allows it
+pub struct AllOf(pub Vec<Arc<dyn PermissionPolicy>>);
+
+#[async_trait]
+impl PermissionPolicy for AllOf {
+    async fn check(
+        &self,
+        player: &PlayerObject,
+        actor: &Actor,
+        action: PlayerAction,
+    ) -> Result<(), PermissionDenied> {
+        for policy in &self.0 {
+            policy.check(player, actor, action).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Allows an action if any policy allows it, for example a DJ or the requester.
+/// If every policy denies the action the last denial is returned
+pub struct AnyOf(pub Vec<Arc<dyn PermissionPolicy>>);
+
+#[async_trait]
+impl PermissionPolicy for AnyOf {
+    async fn check(
+        &self,
+        player: &PlayerObject,
+        actor: &Actor,
+        action: PlayerAction,
+    ) -> Result<(), PermissionDenied> {
+        let mut denied = None;
+        for policy in &self.0 {
+            match policy.check(player, actor, action).await {
+                Ok(()) => return Ok(()),
+                Err(e) => denied = Some(e),
+            }
+        }
+        match denied {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+}
+
+impl PlayerObject {
+    /// Check whether a user may perform an action on this player using the policy set on Charcoal
+    pub async fn authorize(
+        &self,
+        actor: &Actor,
+        action: PlayerAction,
+    ) -> Result<(), PermissionDenied> {
+        let policy = self.permission_policy.read().unwrap().clone();
+        match policy {
+            Some(policy) => policy.check(self, actor, action).await,
+            None => Ok(()),
+        }
+    }
+    /// Perform actions for a user. Every action checks the permission policy before it is sent
+    pub fn acting_for(&self, actor: Actor) -> ActingPlayer {
+        ActingPlayer {
+            player: self.clone(),
+            actor,
+        }
+    }
+}
+
+/// A player that performs actions for a user, see [`PlayerObject::acting_for`]
+#[derive(Clone)]
+pub struct ActingPlayer {
+    player: PlayerObject,
+    actor: Actor,
+}
+
+impl ActingPlayer {
+    pub fn player(&self) -> &PlayerObject {
+        &self.player
+    }
+    pub fn actor(&self) -> &Actor {
+        &self.actor
+    }
+    async fn authorize(&self, action: PlayerAction) -> Result<(), PermissionDenied> {
+        self.player.authorize(&self.actor, action).await
+    }
+    /// Add every track of a playlist to the queue, see `PlayerObject::enqueue_playlist`
+    pub async fn enqueue_playlist(
+        &self,
+        playlist: &Playlist,
+    ) -> Result<usize, EnqueuePlaylistError> {
+        if let Err(e) = self.authorize(PlayerAction::Play).await {
+            return Err(EnqueuePlaylistError::FailedToQueueTrack {
+                queued: 0,
+                source: e.into(),
+            });
+        }
+        self.player.enqueue_playlist(playlist).await
+    }
+}
+
+#[async_trait]
+impl Player for ActingPlayer {
+    async fn play_from_http(&self, url: String) -> Result<(), PlayerActionError> {
+        self.authorize(PlayerAction::Play).await?;
+        self.player.play_from_http(url).await
+    }
+    async fn play_from_youtube(&self, url: String) -> Result<(), PlayerActionError> {
+        self.authorize(PlayerAction::Play).await?;
+        self.player.play_from_youtube(url).await
+    }
+    async fn play_source(&self, source: TrackSource) -> Result<(), PlayerActionError> {
+        self.authorize(PlayerAction::Play).await?;
+        self.player.play_source(source).await
+    }
+}
+
+#[async_trait]
+impl QueueManager for ActingPlayer {
+    async fn enqueue(&self, source: TrackSource) -> Result<(), PlayerActionError> {
+        self.authorize(PlayerAction::Play).await?;
+        self.player.enqueue(source).await
+    }
+    async fn skip(&self) -> Result<(), PlayerActionError> {
+        self.authorize(PlayerAction::Skip).await?;
+        self.player.skip().await
+    }
+    async fn clear_queue(&self) -> Result<(), PlayerActionError> {
+        self.authorize(PlayerAction::ClearQueue).await?;
+        self.player.clear_queue().await
+    }
+    async fn queued_tracks(&self) -> Vec<TrackSource> {
+        self.player.queued_tracks().await
+    }
+    async fn now_playing(&self) -> Option<TrackSource> {
+        self.player.now_playing().await
+    }
+    async fn position(&self) -> Duration {
+        self.player.position().await
+    }
+    async fn loop_mode(&self) -> LoopMode {
+        self.player.loop_mode().await
+    }
+    async fn volume(&self) -> f32 {
+        self.player.volume().await
+    }
+}
+
+#[async_trait]
+impl HistoryManager for ActingPlayer {
+    async fn previous(&self) -> Result<bool, PlayerActionError> {
+        self.authorize(PlayerAction::Previous).await?;
+        self.player.previous().await
+    }
+    async fn history(&self) -> Vec<HistoryEntry> {
+        self.player.history().await
+    }
+}
+
+#[async_trait]
+impl TrackManager for ActingPlayer {
+    async fn set_playback_volume(&self, playback_volume: f32) -> Result<(), TrackActionError> {
+        self.authorize(PlayerAction::Volume).await?;
+        self.player.set_playback_volume(playback_volume).await
+    }
+    async fn force_stop_loop(&self) -> Result<(), TrackActionError> {
+        self.authorize(PlayerAction::Loop).await?;
+        self.player.force_stop_loop().await
+    }
+    async fn loop_indefinitely(&self) -> Result<(), TrackActionError> {
+        self.authorize(PlayerAction::Loop).await?;
+        self.player.loop_indefinitely().await
+    }
+    async fn loop_x_times(&self, times: usize) -> Result<(), TrackActionError> {
+        self.authorize(PlayerAction::Loop).await?;
+        self.player.loop_x_times(times).await
+    }
+    async fn seek_to_position(&self, position: Duration) -> Result<(), TrackActionError> {
+        self.authorize(PlayerAction::Seek).await?;
+        self.player.seek_to_position(position).await
+    }
+    async fn resume_playback(&self) -> Result<(), TrackActionError> {
+        self.authorize(PlayerAction::Resume).await?;
+        self.player.resume_playback().await
+    }
+    async fn pause_playback(&self) -> Result<(), TrackActionError> {
+        self.authorize(PlayerAction::Pause).await?;
+        self.player.pause_playback().await
+    }
+    async fn get_metadata(&self) -> Result<(), TrackActionError> {
+        self.authorize(PlayerAction::View).await?;
+        self.player.get_metadata().await
+    }
+}
+
+#[async_trait]
+impl ChannelManager for ActingPlayer {
+    async fn join_channel(
+        &self,
+        voice_channel_id: VoiceChannelId,
+        create_job: bool,
+    ) -> Result<(), CreateJobError> {
+        self.authorize(PlayerAction::Join).await?;
+        self.player.join_channel(voice_channel_id, create_job).await
+    }
+    async fn exit_channel(&self) -> Result<(), ChannelManagerError> {
+        self.authorize(PlayerAction::Leave).await?;
+        self.player.exit_channel().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::capabilities::Capabilities;
+    use crate::ids::GuildId;
+    use tokio::sync::broadcast;
+
+    fn actor(role_ids: Vec<RoleId>) -> Actor {
+        Actor {
+            user_id: UserId::from_raw("1"),
+            role_ids,
+            voice_channel_id: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn acting_player_checks_policy() {
+        let (bg_tx, mut bg_rx) = broadcast::channel(16);
+        let policy: Arc<dyn PermissionPolicy> =
+            Arc::new(DjRole::new(RoleId::from_raw("dj"), [PlayerAction::Volume]));
+        let player = PlayerObject::new(
+            GuildId::from_raw("1"),
+            bg_tx,
+            Arc::new(std::sync::RwLock::new(Some(policy))),
+            Arc::new(Capabilities::default()),
+        );
+
+        let denied = player
+            .acting_for(actor(vec![]))
+            .set_playback_volume(0.5)
+            .await;
+        assert!(matches!(
+            denied,
+            Err(TrackActionError::Denied {
+                source: PermissionDenied::MissingDjRole { .. }
+            })
+        ));
+        assert!(bg_rx.try_recv().is_err());
+
+        // Allowed actions go on to the player, which has no job yet
+        let allowed = player
+            .acting_for(actor(vec![RoleId::from_raw("dj")]))
+            .set_playback_volume(0.5)
+            .await;
+        assert!(matches!(allowed, Err(TrackActionError::NoActiveJob)));
+    }
+}
@@ -0,0 +1,434 @@
+//! Parses playlist files (M3U, extended M3U, PLS and XSPF) into [`TrackSource`]s that can be queued on a PlayerObject
+
+use crate::actions::player::{PlayerActionError, TrackSource};
+use crate::actions::queue::QueueManager;
+use crate::PlayerObject;
+use snafu::prelude::*;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::time::Duration;
+
+#[derive(Debug, Snafu)]
+pub enum PlaylistError {
+    #[snafu(display("Failed to read playlist file"))]
+    FailedToReadPlaylist { source: std::io::Error },
+    #[snafu(display("Failed to parse XSPF playlist"))]
+    InvalidXspf { source: roxmltree::Error },
+}
+
+/// Problem with a single entry of a playlist. The rest of the playlist is still imported
+#[derive(Debug, Snafu)]
+pub enum PlaylistEntryError {
+    #[snafu(display("Entry {entry}: {url} is not an HTTP(S) URL"))]
+    UnsupportedLocation { entry: usize, url: String },
+    #[snafu(display("Entry {entry}: invalid duration {value}"))]
+    InvalidDuration { entry: usize, value: String },
+    #[snafu(display("Entry {entry}: no location given"))]
+    MissingLocation { entry: usize },
+    #[snafu(display("Entry {entry}: malformed line {line}"))]
+    MalformedLine { entry: usize, line: String },
+}
+
+#[derive(Debug, Snafu)]
+pub enum EnqueuePlaylistError {
+    /// The tracks before the failed one stay queued
+    #[snafu(display("Failed to queue track after queueing {queued} tracks of the playlist"))]
+    FailedToQueueTrack {
+        queued: usize,
+        source: PlayerActionError,
+    },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PlaylistFormat {
+    M3u,
+    ExtendedM3u,
+    Pls,
+    Xspf,
+}
+
+/// Result of parsing a playlist.
+/// `entry` in errors refers to the line number for M3U, the FileN index for PLS and the track index for XSPF
+#[derive(Debug)]
+pub struct Playlist {
+    pub format: PlaylistFormat,
+    pub tracks: Vec<TrackSource>,
+    pub errors: Vec<PlaylistEntryError>,
+}
+
+impl Playlist {
+    /// Parse a playlist from raw bytes, detecting the format from its contents
+    pub fn from_bytes(data: &[u8]) -> Result<Playlist, PlaylistError> {
+        let text = String::from_utf8_lossy(data);
+        let text = text.trim_start_matches('\u{feff}');
+        let start = text.trim_start();
+
+        if start.starts_with("#EXTM3U") {
+            Ok(parse_m3u(text, PlaylistFormat::ExtendedM3u))
+        } else if start.to_ascii_lowercase().starts_with("[playlist]") {
+            Ok(parse_pls(text))
+        } else if start.starts_with("<?xml") || start.starts_with("<playlist") {
+            parse_xspf(text)
+        } else {
+            Ok(parse_m3u(text, PlaylistFormat::M3u))
+        }
+    }
+    /// Read and parse a playlist from a local file
+    pub async fn from_file(path: impl AsRef<Path>) -> Result<Playlist, PlaylistError> {
+        let data = tokio::fs::read(path)
+            .await
+            .context(FailedToReadPlaylistSnafu)?;
+        Playlist::from_bytes(&data)
+    }
+}
+
+fn parse_location(entry: usize, location: &str) -> Result<TrackSource, PlaylistEntryError> {
+    let lowercase = location.to_ascii_lowercase();
+    ensure!(
+        lowercase.starts_with("http://") || lowercase.starts_with("https://"),
+        UnsupportedLocationSnafu {
+            entry,
+            url: location
+        }
+    );
+    Ok(TrackSource::from_url(location.to_string()))
+}
+
+/// Parse a duration in seconds. Negative values mean the duration is unknown
+fn parse_seconds(entry: usize, value: &str) -> Result<Option<Duration>, PlaylistEntryError> {
+    let seconds: f64 = value
+        .trim()
+        .parse()
+        .ok()
+        .filter(|s: &f64| s.is_finite())
+        .context(InvalidDurationSnafu { entry, value })?;
+    if seconds < 0.0 {
+        return Ok(None);
+    }
+    // Values too large for a Duration are rejected instead of panicking
+    Duration::try_from_secs_f64(seconds)
+        .ok()
+        .context(InvalidDurationSnafu { entry, value })
+        .map(Some)
+}
+
+fn parse_m3u(text: &str, format: PlaylistFormat) -> Playlist {
+    let mut tracks = vec![];
+    let mut errors = vec![];
+    let mut title = None;
+    let mut duration = None;
+
+    for (index, line) in text.lines().enumerate() {
+        let entry = index + 1;
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        if let Some(info) = line.strip_prefix("#EXTINF:") {
+            // #EXTINF:<seconds> [attributes],<title>
+            let (seconds, name) = info.split_once(',').unwrap_or((info, ""));
+            let seconds = seconds.split_whitespace().next().unwrap_or_default();
+            match parse_seconds(entry, seconds) {
+                Ok(d) => duration = d,
+                Err(e) => errors.push(e),
+            }
+            if !name.trim().is_empty() {
+                title = Some(name.trim().to_string());
+            }
+            continue;
+        }
+        if line.starts_with('#') {
+            continue;
+        }
+
+        match parse_location(entry, line) {
+            Ok(mut track) => {
+                track.title = title.take();
+                track.duration = duration.take();
+                tracks.push(track);
+            }
+            Err(e) => errors.push(e),
+        }
+        title = None;
+        duration = None;
+    }
+
+    Playlist {
+        format,
+        tracks,
+        errors,
+    }
+}
+
+#[derive(Default)]
+struct PlsEntry {
+    file: Option<String>,
+    title: Option<String>,
+    length: Option<String>,
+}
+
+fn parse_pls(text: &str) -> Playlist {
+    let mut entries: BTreeMap<usize, PlsEntry> = BTreeMap::new();
+    let mut errors = vec![];
+
+    for (index, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('[') || line.starts_with(';') {
+            continue;
+        }
+        let (key, value) = match line.split_once('=') {
+            Some((key, value)) => (key.trim().to_ascii_lowercase(), value.trim().to_string()),
+            None => {
+                errors.push(PlaylistEntryError::MalformedLine {
+                    entry: index + 1,
+                    line: line.to_string(),
+                });
+                continue;
+            }
+        };
+
+        let split_at = key.find(|c: char| c.is_ascii_digit()).unwrap_or(key.len());
+        let (field, number) = key.split_at(split_at);
+        let number = match number.parse::<usize>() {
+            Ok(number) => number,
+            // Header keys such as NumberOfEntries and Version
+            Err(_) => continue,
+        };
+        let pls_entry = entries.entry(number).or_default();
+        match field {
+            "file" => pls_entry.file = Some(value),
+            "title" => pls_entry.title = Some(value),
+            "length" => pls_entry.length = Some(value),
+            _ => {}
+        }
+    }
+
+    let mut tracks = vec![];
+    for (entry, pls_entry) in entries {
+        let track = match pls_entry.file {
+            Some(file) => parse_location(entry, &file),
+            None => Err(PlaylistEntryError::MissingLocation { entry }),
+        };
+        let mut track = match track {
+            Ok(track) => track,
+            Err(e) => {
+                errors.push(e);
+                continue;
+            }
+        };
+        track.title = pls_entry.title.filter(|t| !t.is_empty());
+        if let Some(length) = pls_entry.length {
+            match parse_seconds(entry, &length) {
+                Ok(duration) => track.duration = duration,
+                Err(e) => errors.push(e),
+            }
+        }
+        tracks.push(track);
+    }
+
+    Playlist {
+        format: PlaylistFormat::Pls,
+        tracks,
+        errors,
+    }
+}
+
+fn parse_xspf(text: &str) -> Result<Playlist, PlaylistError> {
+    let document = roxmltree::Document::parse(text).context(InvalidXspfSnafu)?;
+    let mut tracks = vec![];
+    let mut errors = vec![];
+
+    let track_nodes = document.descendants().filter(|n| {
+        n.has_tag_name("track")
+            && n.parent_element()
+                .is_some_and(|p| p.has_tag_name("trackList"))
+    });
+
+    for (index, node) in track_nodes.enumerate() {
+        let entry = index + 1;
+        let child_text = |name: &str| {
+            node.children()
+                .find(|c| c.has_tag_name(name))
+                .and_then(|c| c.text())
+                .map(|t| t.trim().to_string())
+                .filter(|t| !t.is_empty())
+        };
+
+        let mut track = match child_text("location") {
+            Some(location) => match parse_location(entry, &location) {
+                Ok(track) => track,
+                Err(e) => {
+                    errors.push(e);
+                    continue;
+                }
+            },
+            None => {
+                errors.push(PlaylistEntryError::MissingLocation { entry });
+                continue;
+            }
+        };
+        track.title = child_text("title");
+        track.artist = child_text("creator");
+        if let Some(duration) = child_text("duration") {
+            // XSPF durations are in milliseconds
+            match duration.parse::<u64>() {
+                Ok(ms) => track.duration = Some(Duration::from_millis(ms)),
+                Err(_) => errors.push(PlaylistEntryError::InvalidDuration {
+                    entry,
+                    value: duration,
+                }),
+            }
+        }
+        tracks.push(track);
+    }
+
+    Ok(Playlist {
+        format: PlaylistFormat::Xspf,
+        tracks,
+        errors,
+    })
+}
+
+impl PlayerObject {
+    /// Add every track of a playlist to the queue in order. Returns the amount of tracks queued.
+    /// Stops at the first track that fails, the error tells how many tracks were queued before it
+    pub async fn enqueue_playlist(
+        &self,
+        playlist: &Playlist,
+    ) -> Result<usize, EnqueuePlaylistError> {
+        for (queued, track) in playlist.tracks.iter().enumerate() {
+            self.enqueue(track.clone())
+                .await
+                .context(FailedToQueueTrackSnafu { queued })?;
+        }
+        Ok(playlist.tracks.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> Playlist {
+        Playlist::from_bytes(text.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn extended_m3u() {
+        let playlist = parse(
+            "#EXTM3U\n#EXTINF:123,Artist - Title\nhttps://example.com/a.mp3\nhttp://example.com/b.mp3\n",
+        );
+        assert_eq!(playlist.format, PlaylistFormat::ExtendedM3u);
+        assert!(playlist.errors.is_empty());
+        assert_eq!(playlist.tracks.len(), 2);
+        assert_eq!(playlist.tracks[0].title.as_deref(), Some("Artist - Title"));
+        assert_eq!(playlist.tracks[0].duration, Some(Duration::from_secs(123)));
+        // Info from an #EXTINF line only applies to the next entry
+        assert_eq!(playlist.tracks[1].title, None);
+        assert_eq!(playlist.tracks[1].duration, None);
+    }
+
+    #[test]
+    fn m3u_durations() {
+        let playlist = parse(
+            "#EXTM3U\n#EXTINF:-1,Stream\nhttps://example.com/live\n#EXTINF:1e30,Huge\nhttps://example.com/a.mp3\n#EXTINF:abc,Broken\nhttps://example.com/b.mp3\n",
+        );
+        assert_eq!(playlist.tracks.len(), 3);
+        // Negative durations mean the duration is unknown
+        assert_eq!(playlist.tracks[0].duration, None);
+        assert_eq!(playlist.tracks[1].duration, None);
+        assert_eq!(playlist.tracks[2].duration, None);
+        assert!(matches!(
+            playlist.errors.as_slice(),
+            [
+                PlaylistEntryError::InvalidDuration { entry: 4, .. },
+                PlaylistEntryError::InvalidDuration { entry: 6, .. },
+            ]
+        ));
+    }
+
+    #[test]
+    fn m3u_relative_locations() {
+        let playlist =
+            parse("music/a.mp3\n/srv/b.mp3\nfile:///srv/c.mp3\nhttps://example.com/d.mp3\n");
+        assert_eq!(playlist.format, PlaylistFormat::M3u);
+        assert_eq!(playlist.tracks.len(), 1);
+        assert_eq!(playlist.tracks[0].url, "https://example.com/d.mp3");
+        assert_eq!(playlist.errors.len(), 3);
+        assert!(playlist
+            .errors
+            .iter()
+            .all(|e| matches!(e, PlaylistEntryError::UnsupportedLocation { .. })));
+    }
+
+    #[test]
+    fn pls() {
+        let playlist = parse(
+            "[playlist]\nFile1=https://example.com/a.mp3\nTitle1=A\nLength1=60\nFile2=relative.mp3\nTitle3=No file\nFile4=https://example.com/d.mp3\nLength4=1e300\nnot a key value pair\nNumberOfEntries=4\nVersion=2\n",
+        );
+        assert_eq!(playlist.format, PlaylistFormat::Pls);
+        assert_eq!(playlist.tracks.len(), 2);
+        assert_eq!(playlist.tracks[0].title.as_deref(), Some("A"));
+        assert_eq!(playlist.tracks[0].duration, Some(Duration::from_secs(60)));
+        // The entry with a huge length is kept without a duration
+        assert_eq!(playlist.tracks[1].url, "https://example.com/d.mp3");
+        assert_eq!(playlist.tracks[1].duration, None);
+        assert!(matches!(
+            playlist.errors.as_slice(),
+            [
+                PlaylistEntryError::MalformedLine { entry: 9, .. },
+                PlaylistEntryError::UnsupportedLocation { entry: 2, .. },
+                PlaylistEntryError::MissingLocation { entry: 3 },
+                PlaylistEntryError::InvalidDuration { entry: 4, .. },
+            ]
+        ));
+    }
+
+    #[test]
+    fn xspf() {
+        let playlist = parse(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<playlist version="1" xmlns="http://xspf.org/ns/0/">
+  <trackList>
+    <track>
+      <location>https://example.com/a.mp3</location>
+      <title>A</title>
+      <creator>Someone</creator>
+      <duration>90500</duration>
+    </track>
+    <track><title>No location</title></track>
+    <track><location>a.mp3</location></track>
+    <track>
+      <location>https://example.com/d.mp3</location>
+      <duration>-5</duration>
+    </track>
+  </trackList>
+</playlist>"#,
+        );
+        assert_eq!(playlist.format, PlaylistFormat::Xspf);
+        assert_eq!(playlist.tracks.len(), 2);
+        assert_eq!(playlist.tracks[0].title.as_deref(), Some("A"));
+        assert_eq!(playlist.tracks[0].artist.as_deref(), Some("Someone"));
+        assert_eq!(
+            playlist.tracks[0].duration,
+            Some(Duration::from_millis(90_500))
+        );
+        assert_eq!(playlist.tracks[1].duration, None);
+        assert!(matches!(
+            playlist.errors.as_slice(),
+            [
+                PlaylistEntryError::MissingLocation { entry: 2 },
+                PlaylistEntryError::UnsupportedLocation { entry: 3, .. },
+                PlaylistEntryError::InvalidDuration { entry: 4, .. },
+            ]
+        ));
+    }
+
+    #[test]
+    fn malformed_xspf() {
+        assert!(matches!(
+            Playlist::from_bytes(b"<?xml version=\"1.0\"?><playlist><trackList>"),
+            Err(PlaylistError::InvalidXspf { .. })
+        ));
+    }
+}
@@ -1,61 +1,90 @@
 //! Provides ClientBuilder extension for super easy use with serenity
-use futures::executor;
-use std::sync::Arc;
+use async_trait::async_trait;
 
-use crate::{init_charcoal, Charcoal, CharcoalConfig};
+use crate::ids::{GuildId, RoleId, UserId, VoiceChannelId};
+use crate::{init_charcoal, Charcoal, CharcoalConfig, InitError};
 use serenity::prelude::TypeMapKey;
 // pub use serenity::client::ClientBuilder;
 pub use serenity::client::ClientBuilder;
-use serenity::*;
-use tokio::sync::Mutex;
+use serenity::model;
+
+impl From<model::id::GuildId> for GuildId {
+    fn from(id: model::id::GuildId) -> Self {
+        id.0.into()
+    }
+}
+
+impl From<model::id::ChannelId> for VoiceChannelId {
+    fn from(id: model::id::ChannelId) -> Self {
+        id.0.into()
+    }
+}
+
+impl From<model::id::UserId> for UserId {
+    fn from(id: model::id::UserId) -> Self {
+        id.0.into()
+    }
+}
+
+impl From<model::id::RoleId> for RoleId {
+    fn from(id: model::id::RoleId) -> Self {
+        id.0.into()
+    }
+}
+
+pub mod commands;
+pub mod lookup;
+pub mod now_playing;
+pub mod voice_state;
+
+pub use lookup::{get_charcoal, get_or_create_player_for, get_player, get_player_for, LookupError};
 
 pub struct CharcoalKey;
 
 impl TypeMapKey for CharcoalKey {
-    type Value = Arc<Mutex<Charcoal>>;
+    type Value = Charcoal;
 }
 
-pub trait SerenityInit {
-    #[must_use]
+#[async_trait]
+pub trait SerenityInit: Sized {
     /// Initializes charcoal and registers it in the Serenity type-map
-    fn register_charcoal(self, broker: String, config: CharcoalConfig) -> Self;
+    async fn register_charcoal(
+        self,
+        broker: String,
+        config: CharcoalConfig,
+    ) -> Result<Self, InitError>;
+    #[must_use]
+    /// Registers an already initialized charcoal instance in the Serenity type-map
+    fn register_charcoal_instance(self, charcoal: Charcoal) -> Self;
 }
 
+#[async_trait]
 impl SerenityInit for ClientBuilder {
-    fn register_charcoal(self, broker: String, config: CharcoalConfig) -> Self {
-        let c = init_charcoal(broker, config);
-        self.type_map_insert::<CharcoalKey>(executor::block_on(c))
+    async fn register_charcoal(
+        self,
+        broker: String,
+        config: CharcoalConfig,
+    ) -> Result<Self, InitError> {
+        let charcoal = init_charcoal(broker, config).await?;
+        Ok(self.register_charcoal_instance(charcoal))
+    }
+    fn register_charcoal_instance(self, charcoal: Charcoal) -> Self {
+        self.type_map_insert::<CharcoalKey>(charcoal)
     }
 }
 
 #[macro_export]
+#[deprecated(note = "use `charcoal_client::serenity::get_player_for` instead")]
 macro_rules! get_handler_from_serenity_mutable {
     ($ctx: expr,$msg: expr,$reference: ident) => {
-        let r = $ctx.data.read().await;
-        // Get the GuildID
-        let guild = $msg.guild(&$ctx.cache).unwrap();
-        let guild_id = guild.id;
-        // Get the charcoal manager from the serenity typemap
-        let manager = r.get::<CharcoalKey>();
-        let mut mx = manager.unwrap().lock().await;
-        // Get the PlayerObject
-        let mut players = mx.players.write().await;
-        $reference = players.get_mut(&guild_id.to_string());
+        $reference = $crate::serenity::get_player_for(&$ctx, &$msg).await.ok();
     };
 }
 
 #[macro_export]
+#[deprecated(note = "use `charcoal_client::serenity::get_player_for` instead")]
 macro_rules! get_handler_from_serenity {
     ($ctx: expr,$msg: expr,$reference: ident) => {
-        let r = $ctx.data.read().await;
-        // Get the GuildID
-        let guild = $msg.guild(&$ctx.cache).unwrap();
-        let guild_id = guild.id;
-        // Get the charcoal manager from the serenity typemap
-        let manager = r.get::<CharcoalKey>();
-        let mut mx = manager.unwrap().lock().await;
-        // Get the PlayerObject
-        let players = mx.players.read().await;
-        $reference = players.get(&guild_id.to_string());
+        $reference = $crate::serenity::get_player_for(&$ctx, &$msg).await.ok();
     };
 }
@@ -0,0 +1,701 @@
+//! Ready-made slash commands for controlling PlayerObjects.
+//!
+//! Register the commands with [`MusicCommands::register_global`] or [`MusicCommands::register_in_guild`]
+//! and pass every interaction to [`MusicCommands::handle_interaction`] from your `EventHandler`.
+//! Responses can be changed by implementing [`ResponseFormatter`]
+
+use crate::actions::channel_manager::{ChannelManager, ChannelManagerError, CreateJobError};
+use crate::actions::player::{PlayerActionError, TrackSource};
+use crate::actions::queue::{LoopMode, QueueManager};
+use crate::actions::track_manager::{TrackActionError, TrackManager};
+use crate::ids::{GuildId, RoleId, VoiceChannelId};
+use crate::now_playing::{format_duration, track_name};
+use crate::permissions::{Actor, PermissionDenied, PlayerAction};
+use crate::serenity::lookup::{get_charcoal, LookupError};
+use crate::votes::{VoteConfig, VoteError, VoteOutcome};
+use crate::{Charcoal, PlayerHandle};
+use serenity::builder::{CreateApplicationCommand, CreateApplicationCommands};
+use serenity::client::Context;
+use serenity::http::Http;
+use serenity::model::application::command::{Command, CommandOptionType};
+use serenity::model::application::interaction::application_command::ApplicationCommandInteraction;
+use serenity::model::application::interaction::{Interaction, InteractionResponseType};
+use serenity::model::id;
+use snafu::prelude::*;
+use std::time::Duration;
+
+/// Highest volume that can be set through the volume command, in percent.
+/// The Hearth server does not soft-clip so anything above 100% may clip
+const MAX_VOLUME: i64 = 100;
+/// Amount of queued tracks listed by the default queue response
+const QUEUE_DISPLAY_LIMIT: usize = 10;
+
+#[derive(Debug, Snafu)]
+pub enum CommandError {
+    #[snafu(display("Failed to look up player"))]
+    Lookup { source: LookupError },
+    #[snafu(display("Commands can only be used in a guild"))]
+    NotInGuild,
+    #[snafu(display("You must be in a voice channel"))]
+    NotInVoiceChannel,
+    #[snafu(display("Missing option {name}"))]
+    MissingOption { name: String },
+    #[snafu(display("Invalid value for option {name}: {reason}"))]
+    InvalidOption { name: String, reason: String },
+    #[snafu(display("{source}"))]
+    PermissionDenied { source: PermissionDenied },
+    #[snafu(display("{source}"))]
+    Vote { source: VoteError },
+    #[snafu(display("Nothing is playing"))]
+    NothingPlaying,
+    #[snafu(display("Failed to join channel"))]
+    FailedToJoin { source: CreateJobError },
+    #[snafu(display("Failed to leave channel"))]
+    FailedToLeave {
+        #[snafu(source(from(ChannelManagerError, Box::new)))]
+        source: Box<ChannelManagerError>,
+    },
+    #[snafu(display("Failed to start playback"))]
+    FailedToPlay {
+        #[snafu(source(from(PlayerActionError, Box::new)))]
+        source: Box<PlayerActionError>,
+    },
+    #[snafu(display("Failed to control playback"))]
+    FailedToControlTrack { source: TrackActionError },
+}
+
+/// The commands provided by [`MusicCommands`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum MusicCommand {
+    Join,
+    Leave,
+    Play,
+    Pause,
+    Resume,
+    Volume,
+    Seek,
+    Loop,
+    Queue,
+    NowPlaying,
+}
+
+impl MusicCommand {
+    pub const ALL: [MusicCommand; 10] = [
+        MusicCommand::Join,
+        MusicCommand::Leave,
+        MusicCommand::Play,
+        MusicCommand::Pause,
+        MusicCommand::Resume,
+        MusicCommand::Volume,
+        MusicCommand::Seek,
+        MusicCommand::Loop,
+        MusicCommand::Queue,
+        MusicCommand::NowPlaying,
+    ];
+
+    /// Name the command is registered under
+    pub fn name(&self) -> &'static str {
+        match self {
+            MusicCommand::Join => "join",
+            MusicCommand::Leave => "leave",
+            MusicCommand::Play => "play",
+            MusicCommand::Pause => "pause",
+            MusicCommand::Resume => "resume",
+            MusicCommand::Volume => "volume",
+            MusicCommand::Seek => "seek",
+            MusicCommand::Loop => "loop",
+            MusicCommand::Queue => "queue",
+            MusicCommand::NowPlaying => "nowplaying",
+        }
+    }
+    /// The action checked against the permission policy before running the command
+    pub fn action(&self) -> PlayerAction {
+        match self {
+            MusicCommand::Join => PlayerAction::Join,
+            MusicCommand::Leave => PlayerAction::Leave,
+            MusicCommand::Play => PlayerAction::Play,
+            MusicCommand::Pause => PlayerAction::Pause,
+            MusicCommand::Resume => PlayerAction::Resume,
+            MusicCommand::Volume => PlayerAction::Volume,
+            MusicCommand::Seek => PlayerAction::Seek,
+            MusicCommand::Loop => PlayerAction::Loop,
+            MusicCommand::Queue | MusicCommand::NowPlaying => PlayerAction::View,
+        }
+    }
+    pub fn from_name(name: &str) -> Option<MusicCommand> {
+        MusicCommand::ALL.into_iter().find(|c| c.name() == name)
+    }
+    /// Fill in the name, description and options of the command
+    pub fn create(&self, command: &mut CreateApplicationCommand) {
+        command.name(self.name()).dm_permission(false);
+        match self {
+            MusicCommand::Join => {
+                command.description("Join your voice channel");
+            }
+            MusicCommand::Leave => {
+                command.description("Leave the voice channel");
+            }
+            MusicCommand::Play => {
+                command
+                    .description("Play a track or add it to the queue")
+                    .create_option(|o| {
+                        o.name("url")
+                            .description("Youtube or direct HTTP link to the track")
+                            .kind(CommandOptionType::String)
+                            .required(true)
+                    });
+            }
+            MusicCommand::Pause => {
+                command.description("Pause playback");
+            }
+            MusicCommand::Resume => {
+                command.description("Resume playback");
+            }
+            MusicCommand::Volume => {
+                command
+                    .description("Set the playback volume")
+                    .create_option(|o| {
+                        o.name("percent")
+                            .description("Volume in percent")
+                            .kind(CommandOptionType::Integer)
+                            .min_int_value(0)
+                            .max_int_value(MAX_VOLUME)
+                            .required(true)
+                    });
+            }
+            MusicCommand::Seek => {
+                command
+                    .description("Seek to a position in the current track")
+                    .create_option(|o| {
+                        o.name("position")
+                            .description("Position as seconds, mm:ss or hh:mm:ss")
+                            .kind(CommandOptionType::String)
+                            .required(true)
+                    });
+            }
+            MusicCommand::Loop => {
+                command
+                    .description("Loop the current track")
+                    .create_option(|o| {
+                        o.name("mode")
+                            .description("How to loop the track")
+                            .kind(CommandOptionType::String)
+                            .add_string_choice("off", "off")
+                            .add_string_choice("forever", "forever")
+                            .add_string_choice("times", "times")
+                            .required(true)
+                    })
+                    .create_option(|o| {
+                        o.name("times")
+                            .description("How many times to loop when mode is times")
+                            .kind(CommandOptionType::Integer)
+                            .min_int_value(1)
+                    });
+            }
+            MusicCommand::Queue => {
+                command.description("Show the queue");
+            }
+            MusicCommand::NowPlaying => {
+                command.description("Show the track that is currently playing");
+            }
+        }
+    }
+}
+
+/// Result of a command that succeeded
+#[derive(Clone, Debug)]
+pub enum CommandOutcome {
+    Joined {
+        voice_channel_id: VoiceChannelId,
+    },
+    Left,
+    Playing {
+        source: TrackSource,
+        /// Whether the track was added to the queue instead of starting right away
+        queued: bool,
+    },
+    Paused,
+    Resumed,
+    /// Volume in percent
+    VolumeSet(i64),
+    Seeked(Duration),
+    LoopSet(LoopMode),
+    Queue {
+        now_playing: Option<TrackSource>,
+        tracks: Vec<TrackSource>,
+    },
+    NowPlaying {
+        source: TrackSource,
+        position: Duration,
+    },
+    /// The permission policy requires a vote and more votes are needed
+    VotePending {
+        action: PlayerAction,
+        votes: usize,
+        required: usize,
+        already_voted: bool,
+    },
+}
+
+/// Turns command results into response messages. Override the methods to change what the bot replies with
+pub trait ResponseFormatter: Send + Sync {
+    fn success(&self, outcome: &CommandOutcome) -> String {
+        match outcome {
+            CommandOutcome::Joined { voice_channel_id } => {
+                format!("Joined <#{}>", voice_channel_id)
+            }
+            CommandOutcome::Left => "Left the voice channel".to_string(),
+            CommandOutcome::Playing { source, queued } => {
+                let verb = if *queued { "Queued" } else { "Playing" };
+                format!("{} {}", verb, track_name(source))
+            }
+            CommandOutcome::Paused => "Paused".to_string(),
+            CommandOutcome::Resumed => "Resumed".to_string(),
+            CommandOutcome::VolumeSet(volume) => format!("Set volume to {}%", volume),
+            CommandOutcome::Seeked(position) => format!("Seeked to {}", format_duration(*position)),
+            CommandOutcome::LoopSet(LoopMode::Off) => "Stopped looping".to_string(),
+            CommandOutcome::LoopSet(LoopMode::Forever) => "Looping forever".to_string(),
+            CommandOutcome::LoopSet(LoopMode::Times(times)) => format!("Looping {} times", times),
+            CommandOutcome::Queue {
+                now_playing,
+                tracks,
+            } => {
+                let mut lines = vec![match now_playing {
+                    Some(source) => format!("Now playing: {}", track_name(source)),
+                    None => "Nothing is playing".to_string(),
+                }];
+                lines.extend(
+                    tracks
+                        .iter()
+                        .take(QUEUE_DISPLAY_LIMIT)
+                        .enumerate()
+                        .map(|(i, source)| format!("{}. {}", i + 1, track_name(source))),
+                );
+                if tracks.len() > QUEUE_DISPLAY_LIMIT {
+                    lines.push(format!(
+                        "...and {} more",
+                        tracks.len() - QUEUE_DISPLAY_LIMIT
+                    ));
+                }
+                lines.join("\n")
+            }
+            CommandOutcome::NowPlaying { source, position } => {
+                let total = source
+                    .duration
+                    .map(|d| format!(" / {}", format_duration(d)))
+                    .unwrap_or_default();
+                format!(
+                    "Now playing: {} [{}{}]",
+                    track_name(source),
+                    format_duration(*position),
+                    total
+                )
+            }
+            CommandOutcome::VotePending {
+                action,
+                votes,
+                required,
+                already_voted,
+            } => {
+                let prefix = if *already_voted {
+                    "You already voted"
+                } else {
+                    "Vote counted"
+                };
+                format!("{}, {}/{} votes to {}", prefix, votes, required, action)
+            }
+        }
+    }
+    fn failure(&self, error: &CommandError) -> String {
+        match error {
+            CommandError::Lookup {
+                source: LookupError::NoPlayer { .. },
+            } => "Not in a voice channel, use /join first".to_string(),
+            e => e.to_string(),
+        }
+    }
+}
+
+/// Formatter used when no other formatter is given
+pub struct DefaultFormatter;
+
+impl ResponseFormatter for DefaultFormatter {}
+
+/// Parses seconds, mm:ss or hh:mm:ss
+fn parse_position(value: &str) -> Option<Duration> {
+    let parts = value
+        .trim()
+        .split(':')
+        .map(|p| p.parse::<u64>().ok())
+        .collect::<Option<Vec<u64>>>()?;
+    let seconds = match parts.as_slice() {
+        [s] => *s,
+        [m, s] if *s < 60 => m * 60 + s,
+        [h, m, s] if *m < 60 && *s < 60 => h * 3600 + m * 60 + s,
+        _ => return None,
+    };
+    Some(Duration::from_secs(seconds))
+}
+
+/// A set of slash commands that control the PlayerObject of the guild they are used in
+pub struct MusicCommands {
+    commands: Vec<MusicCommand>,
+    formatter: Box<dyn ResponseFormatter>,
+    vote_config: VoteConfig,
+}
+
+impl Default for MusicCommands {
+    fn default() -> Self {
+        MusicCommands {
+            commands: MusicCommand::ALL.to_vec(),
+            formatter: Box::new(DefaultFormatter),
+            vote_config: VoteConfig::default(),
+        }
+    }
+}
+
+impl MusicCommands {
+    /// Create a kit containing every command
+    pub fn new() -> Self {
+        MusicCommands::default()
+    }
+    /// Only provide the given commands
+    pub fn only(mut self, commands: &[MusicCommand]) -> Self {
+        self.commands = commands.to_vec();
+        self
+    }
+    /// Settings for votes started when the permission policy requires one
+    pub fn with_vote_config(mut self, vote_config: VoteConfig) -> Self {
+        self.vote_config = vote_config;
+        self
+    }
+    /// Use a custom formatter for responses
+    pub fn with_formatter(mut self, formatter: impl ResponseFormatter + 'static) -> Self {
+        self.formatter = Box::new(formatter);
+        self
+    }
+    pub fn commands(&self) -> &[MusicCommand] {
+        &self.commands
+    }
+    /// Add the commands to a builder. Useful if your bot registers its own commands as well
+    pub fn create_commands<'a>(
+        &self,
+        builder: &'a mut CreateApplicationCommands,
+    ) -> &'a mut CreateApplicationCommands {
+        for command in &self.commands {
+            builder.create_application_command(|c| {
+                command.create(c);
+                c
+            });
+        }
+        builder
+    }
+    /// Register the commands globally. This replaces all other global commands of the bot
+    pub async fn register_global(&self, http: impl AsRef<Http>) -> serenity::Result<Vec<Command>> {
+        Command::set_global_application_commands(http, |c| self.create_commands(c)).await
+    }
+    /// Register the commands in a single guild. This replaces all other commands of the bot in that guild
+    pub async fn register_in_guild(
+        &self,
+        http: impl AsRef<Http>,
+        guild_id: id::GuildId,
+    ) -> serenity::Result<Vec<Command>> {
+        guild_id
+            .set_application_commands(http, |c| self.create_commands(c))
+            .await
+    }
+    /// Handle an interaction if it is one of our commands. Returns false if the interaction was not handled
+    pub async fn handle_interaction(
+        &self,
+        ctx: &Context,
+        interaction: &Interaction,
+    ) -> serenity::Result<bool> {
+        match interaction {
+            Interaction::ApplicationCommand(command) => self.handle_command(ctx, command).await,
+            _ => Ok(false),
+        }
+    }
+    /// Handle a slash command if it is one of our commands. Returns false if the command was not handled
+    pub async fn handle_command(
+        &self,
+        ctx: &Context,
+        interaction: &ApplicationCommandInteraction,
+    ) -> serenity::Result<bool> {
+        let command = match MusicCommand::from_name(&interaction.data.name) {
+            Some(command) if self.commands.contains(&command) => command,
+            _ => return Ok(false),
+        };
+
+        // Joining can take longer than Discord waits for a response, so answer once the command is done
+        interaction
+            .create_interaction_response(&ctx.http, |r| {
+                r.kind(InteractionResponseType::DeferredChannelMessageWithSource)
+            })
+            .await?;
+
+        match self.run(ctx, command, interaction).await {
+            Ok(outcome) => {
+                let content = self.formatter.success(&outcome);
+                interaction
+                    .edit_original_interaction_response(&ctx.http, |r| r.content(content))
+                    .await?;
+            }
+            Err(e) => {
+                // Only followups can be ephemeral once the response was deferred
+                let content = self.formatter.failure(&e);
+                interaction
+                    .delete_original_interaction_response(&ctx.http)
+                    .await?;
+                interaction
+                    .create_followup_message(&ctx.http, |f| f.content(content).ephemeral(true))
+                    .await?;
+            }
+        }
+        Ok(true)
+    }
+    /// Run a command without responding to it
+    pub async fn run(
+        &self,
+        ctx: &Context,
+        command: MusicCommand,
+        interaction: &ApplicationCommandInteraction,
+    ) -> Result<CommandOutcome, CommandError> {
+        let guild_id = interaction.guild_id.context(NotInGuildSnafu)?;
+        let charcoal = get_charcoal(ctx).await.context(LookupSnafu)?;
+        let actor = interaction_actor(ctx, guild_id, interaction);
+
+        let guild_id = GuildId::from(guild_id);
+
+        if command == MusicCommand::Join {
+            ensure!(actor.voice_channel_id.is_some(), NotInVoiceChannelSnafu);
+            // Check before creating the player so denied users don't leave one behind
+            charcoal
+                .authorize(&guild_id, &actor, command.action())
+                .await
+                .context(PermissionDeniedSnafu)?;
+            let player = charcoal.get_or_create_player(&guild_id);
+            return run_with_player(command, &charcoal, &player, &actor, interaction).await;
+        }
+
+        let player = charcoal
+            .player(&guild_id)
+            .ok_or(LookupError::NoPlayer { guild_id })
+            .context(LookupSnafu)?;
+        match player.authorize(&actor, command.action()).await {
+            Ok(()) => {}
+            Err(PermissionDenied::VoteRequired { action }) => {
+                let outcome = player
+                    .vote(actor.user_id.clone(), action, &self.vote_config)
+                    .await
+                    .context(VoteSnafu)?;
+                let (votes, required, already_voted) = match outcome {
+                    VoteOutcome::Passed { .. } => {
+                        return run_with_player(command, &charcoal, &player, &actor, interaction)
+                            .await
+                    }
+                    VoteOutcome::Pending { votes, required } => (votes, required, false),
+                    VoteOutcome::AlreadyVoted { votes, required } => (votes, required, true),
+                };
+                return Ok(CommandOutcome::VotePending {
+                    action,
+                    votes,
+                    required,
+                    already_voted,
+                });
+            }
+            Err(e) => return Err(e).context(PermissionDeniedSnafu),
+        }
+        run_with_player(command, &charcoal, &player, &actor, interaction).await
+    }
+}
+
+/// Describe the user that used a command for permission checks
+pub fn interaction_actor(
+    ctx: &Context,
+    guild_id: id::GuildId,
+    interaction: &ApplicationCommandInteraction,
+) -> Actor {
+    let voice_channel_id = ctx
+        .cache
+        .guild(guild_id)
+        .and_then(|g| g.voice_states.get(&interaction.user.id)?.channel_id);
+    Actor {
+        user_id: interaction.user.id.into(),
+        role_ids: interaction
+            .member
+            .iter()
+            .flat_map(|m| m.roles.iter().map(|r| RoleId::from(*r)))
+            .collect(),
+        voice_channel_id: voice_channel_id.map(Into::into),
+    }
+}
+
+async fn run_with_player(
+    command: MusicCommand,
+    charcoal: &Charcoal,
+    player: &PlayerHandle,
+    actor: &Actor,
+    interaction: &ApplicationCommandInteraction,
+) -> Result<CommandOutcome, CommandError> {
+    let option = |name: &str| {
+        interaction
+            .data
+            .options
+            .iter()
+            .find(|o| o.name == name)
+            .and_then(|o| o.value.clone())
+    };
+    let string_option = |name: &str| {
+        option(name)
+            .and_then(|v| v.as_str().map(str::to_string))
+            .context(MissingOptionSnafu { name })
+    };
+    let int_option = |name: &str| {
+        option(name)
+            .and_then(|v| v.as_i64())
+            .context(MissingOptionSnafu { name })
+    };
+
+    match command {
+        MusicCommand::Join => {
+            let voice_channel_id = actor
+                .voice_channel_id
+                .clone()
+                .context(NotInVoiceChannelSnafu)?;
+            // Reuse the player's job if it has one, otherwise create one.
+            // A player left without a job by a failed join is removed so the next join starts over
+            let create_job = player.job().await.is_none();
+            if let Err(e) = player
+                .join_channel(voice_channel_id.clone(), create_job)
+                .await
+            {
+                if player.job().await.is_none() {
+                    charcoal.remove_player(player.guild_id(), false).await;
+                }
+                return Err(e).context(FailedToJoinSnafu);
+            }
+            Ok(CommandOutcome::Joined { voice_channel_id })
+        }
+        MusicCommand::Leave => {
+            let removed = charcoal.remove_player(player.guild_id(), true).await;
+            if let Some(e) = removed.leave_error {
+                return Err(e).context(FailedToLeaveSnafu);
+            }
+            Ok(CommandOutcome::Left)
+        }
+        MusicCommand::Play => {
+            let url = string_option("url")?;
+            let lowercase = url.to_ascii_lowercase();
+            ensure!(
+                lowercase.starts_with("http://") || lowercase.starts_with("https://"),
+                InvalidOptionSnafu {
+                    name: "url",
+                    reason: "must be an HTTP(S) link"
+                }
+            );
+            let queued = player.now_playing().await.is_some();
+            let source = TrackSource::from_url(url).with_requester(actor.user_id.clone());
+            player
+                .enqueue(source.clone())
+                .await
+                .context(FailedToPlaySnafu)?;
+            Ok(CommandOutcome::Playing { source, queued })
+        }
+        MusicCommand::Pause => {
+            ensure!(player.now_playing().await.is_some(), NothingPlayingSnafu);
+            player
+                .pause_playback()
+                .await
+                .context(FailedToControlTrackSnafu)?;
+            Ok(CommandOutcome::Paused)
+        }
+        MusicCommand::Resume => {
+            ensure!(player.now_playing().await.is_some(), NothingPlayingSnafu);
+            player
+                .resume_playback()
+                .await
+                .context(FailedToControlTrackSnafu)?;
+            Ok(CommandOutcome::Resumed)
+        }
+        MusicCommand::Volume => {
+            let percent = int_option("percent")?;
+            ensure!(
+                (0..=MAX_VOLUME).contains(&percent),
+                InvalidOptionSnafu {
+                    name: "percent",
+                    reason: format!("must be between 0 and {}", MAX_VOLUME)
+                }
+            );
+            player
+                .set_playback_volume(percent as f32 / 100.0)
+                .await
+                .context(FailedToControlTrackSnafu)?;
+            Ok(CommandOutcome::VolumeSet(percent))
+        }
+        MusicCommand::Seek => {
+            let value = string_option("position")?;
+            let position = parse_position(&value).context(InvalidOptionSnafu {
+                name: "position",
+                reason: "expected seconds, mm:ss or hh:mm:ss",
+            })?;
+            let now_playing = player.now_playing().await.context(NothingPlayingSnafu)?;
+            if let Some(duration) = now_playing.duration {
+                ensure!(
+                    position <= duration,
+                    InvalidOptionSnafu {
+                        name: "position",
+                        reason: format!("track is only {} long", format_duration(duration))
+                    }
+                );
+            }
+            player
+                .seek_to_position(position)
+                .await
+                .context(FailedToControlTrackSnafu)?;
+            Ok(CommandOutcome::Seeked(position))
+        }
+        MusicCommand::Loop => {
+            ensure!(player.now_playing().await.is_some(), NothingPlayingSnafu);
+            let mode = match string_option("mode")?.as_str() {
+                "off" => LoopMode::Off,
+                "forever" => LoopMode::Forever,
+                "times" => {
+                    let times = int_option("times")?;
+                    ensure!(
+                        times >= 1,
+                        InvalidOptionSnafu {
+                            name: "times",
+                            reason: "must be at least 1"
+                        }
+                    );
+                    LoopMode::Times(times as usize)
+                }
+                _ => {
+                    return InvalidOptionSnafu {
+                        name: "mode",
+                        reason: "must be off, forever or times",
+                    }
+                    .fail()
+                }
+            };
+            let result = match mode {
+                LoopMode::Off => player.force_stop_loop().await,
+                LoopMode::Forever => player.loop_indefinitely().await,
+                LoopMode::Times(times) => player.loop_x_times(times).await,
+            };
+            result.context(FailedToControlTrackSnafu)?;
+            Ok(CommandOutcome::LoopSet(mode))
+        }
+        MusicCommand::Queue => Ok(CommandOutcome::Queue {
+            now_playing: player.now_playing().await,
+            tracks: player.queued_tracks().await,
+        }),
+        MusicCommand::NowPlaying => {
+            let source = player.now_playing().await.context(NothingPlayingSnafu)?;
+            Ok(CommandOutcome::NowPlaying {
+                source,
+                position: player.position().await,
+            })
+        }
+    }
+}
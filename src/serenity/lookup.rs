@@ -0,0 +1,103 @@
+//! Helpers for getting the Charcoal instance and PlayerObjects out of a serenity `Context` without panicking
+
+use crate::ids::GuildId;
+use crate::serenity::CharcoalKey;
+use crate::{Charcoal, PlayerHandle};
+use serenity::client::Context;
+use serenity::model::application::interaction::application_command::ApplicationCommandInteraction;
+use serenity::model::application::interaction::message_component::MessageComponentInteraction;
+use serenity::model::application::interaction::Interaction;
+use serenity::model::channel::Message;
+use serenity::model::id;
+use snafu::prelude::*;
+
+#[derive(Debug, Snafu)]
+pub enum LookupError {
+    #[snafu(display("Charcoal has not been registered in the serenity type-map"))]
+    CharcoalNotRegistered,
+    #[snafu(display("Message or interaction was not sent in a guild"))]
+    NotInGuild,
+    #[snafu(display("No player exists for guild {guild_id}"))]
+    NoPlayer { guild_id: GuildId },
+}
+
+/// Anything serenity gives us that may have been sent in a guild
+pub trait GuildSource {
+    fn source_guild_id(&self) -> Option<id::GuildId>;
+}
+
+impl<T: GuildSource + ?Sized> GuildSource for &T {
+    fn source_guild_id(&self) -> Option<id::GuildId> {
+        (**self).source_guild_id()
+    }
+}
+
+impl GuildSource for Message {
+    fn source_guild_id(&self) -> Option<id::GuildId> {
+        self.guild_id
+    }
+}
+
+impl GuildSource for ApplicationCommandInteraction {
+    fn source_guild_id(&self) -> Option<id::GuildId> {
+        self.guild_id
+    }
+}
+
+impl GuildSource for MessageComponentInteraction {
+    fn source_guild_id(&self) -> Option<id::GuildId> {
+        self.guild_id
+    }
+}
+
+impl GuildSource for Interaction {
+    fn source_guild_id(&self) -> Option<id::GuildId> {
+        match self {
+            Interaction::Ping(_) => None,
+            Interaction::ApplicationCommand(i) => i.guild_id,
+            Interaction::MessageComponent(i) => i.guild_id,
+            Interaction::Autocomplete(i) => i.guild_id,
+            Interaction::ModalSubmit(i) => i.guild_id,
+        }
+    }
+}
+
+/// Get the Charcoal instance registered with `SerenityInit`
+pub async fn get_charcoal(ctx: &Context) -> Result<Charcoal, LookupError> {
+    ctx.data
+        .read()
+        .await
+        .get::<CharcoalKey>()
+        .cloned()
+        .context(CharcoalNotRegisteredSnafu)
+}
+
+/// Get the PlayerObject of a guild
+pub async fn get_player(
+    ctx: &Context,
+    guild_id: impl Into<GuildId>,
+) -> Result<PlayerHandle, LookupError> {
+    let guild_id = guild_id.into();
+    get_charcoal(ctx)
+        .await?
+        .player(&guild_id)
+        .context(NoPlayerSnafu { guild_id })
+}
+
+/// Get the PlayerObject of the guild a message or interaction was sent in
+pub async fn get_player_for(
+    ctx: &Context,
+    source: &impl GuildSource,
+) -> Result<PlayerHandle, LookupError> {
+    let guild_id = source.source_guild_id().context(NotInGuildSnafu)?;
+    get_player(ctx, guild_id).await
+}
+
+/// Get or create the PlayerObject of the guild a message or interaction was sent in
+pub async fn get_or_create_player_for(
+    ctx: &Context,
+    source: &impl GuildSource,
+) -> Result<PlayerHandle, LookupError> {
+    let guild_id = source.source_guild_id().context(NotInGuildSnafu)?;
+    Ok(get_charcoal(ctx).await?.get_or_create_player(guild_id))
+}
@@ -0,0 +1,61 @@
+//! Renders [`NowPlaying`] snapshots as serenity embeds
+
+use crate::now_playing::{NowPlaying, ProgressBar};
+use serenity::builder::CreateEmbed;
+
+/// Layout of a now playing embed. Every string may contain the placeholders listed in [`crate::now_playing`]
+#[derive(Clone, Debug)]
+pub struct EmbedTemplate {
+    pub title: String,
+    pub description: String,
+    /// Name, value and whether the field is inline
+    pub fields: Vec<(String, String, bool)>,
+    pub footer: Option<String>,
+    pub colour: Option<u32>,
+    pub bar: ProgressBar,
+}
+
+impl Default for EmbedTemplate {
+    fn default() -> Self {
+        EmbedTemplate {
+            title: "{title}".to_string(),
+            description: "{artist}\n`{bar}`\n{position} / {duration}".to_string(),
+            fields: vec![
+                ("Volume".to_string(), "{volume}".to_string(), true),
+                ("Loop".to_string(), "{loop}".to_string(), true),
+                ("Queue".to_string(), "{queue}".to_string(), true),
+            ],
+            footer: Some("Up next: {next}".to_string()),
+            colour: None,
+            bar: ProgressBar::default(),
+        }
+    }
+}
+
+impl NowPlaying {
+    /// Render as an embed using the default template
+    pub fn to_embed(&self) -> CreateEmbed {
+        self.to_embed_with(&EmbedTemplate::default())
+    }
+    /// Render as an embed using a custom template
+    pub fn to_embed_with(&self, template: &EmbedTemplate) -> CreateEmbed {
+        let render = |text: &str| self.render(text, &template.bar);
+        let mut embed = CreateEmbed::default();
+        embed
+            .title(render(&template.title))
+            .description(render(&template.description).trim());
+        if let Some(source) = &self.source {
+            embed.url(&source.url);
+        }
+        for (name, value, inline) in &template.fields {
+            embed.field(render(name), render(value), *inline);
+        }
+        if let Some(footer) = &template.footer {
+            embed.footer(|f| f.text(render(footer)));
+        }
+        if let Some(colour) = template.colour {
+            embed.colour(colour);
+        }
+        embed
+    }
+}
@@ -0,0 +1,113 @@
+//! Serenity event handler that keeps players in sync with voice state updates
+
+use crate::ids::{GuildId, UserId};
+use crate::serenity::CharcoalKey;
+use async_trait::async_trait;
+use log::error;
+use serenity::client::{Context, EventHandler, RawEventHandler};
+use serenity::model::event::Event;
+use serenity::model::voice::VoiceState;
+
+/// Watches voice state updates to follow the bot when it is moved, clean up its job when it is disconnected
+/// and keep track of who is listening.
+///
+/// Register it with `ClientBuilder::raw_event_handler`, or call [`handle_voice_state_update`] from your own `EventHandler`
+#[derive(Default)]
+pub struct VoiceStateTracker {
+    leave_when_alone: bool,
+}
+
+impl VoiceStateTracker {
+    pub fn new() -> Self {
+        VoiceStateTracker::default()
+    }
+    /// Leave the channel and remove the player once the last listener leaves
+    pub fn leave_when_alone(mut self, leave_when_alone: bool) -> Self {
+        self.leave_when_alone = leave_when_alone;
+        self
+    }
+}
+
+#[async_trait]
+impl EventHandler for VoiceStateTracker {
+    async fn voice_state_update(&self, ctx: Context, _old: Option<VoiceState>, new: VoiceState) {
+        self.handle(&ctx, &new).await;
+    }
+}
+
+#[async_trait]
+impl RawEventHandler for VoiceStateTracker {
+    async fn raw_event(&self, ctx: Context, ev: Event) {
+        if let Event::VoiceStateUpdate(update) = ev {
+            self.handle(&ctx, &update.voice_state).await;
+        }
+    }
+}
+
+impl VoiceStateTracker {
+    async fn handle(&self, ctx: &Context, state: &VoiceState) {
+        let remaining = handle_voice_state_update(ctx, state).await;
+        if self.leave_when_alone && remaining == Some(0) {
+            let guild_id = match state.guild_id {
+                Some(guild_id) => guild_id,
+                None => return,
+            };
+            let charcoal = match ctx.data.read().await.get::<CharcoalKey>() {
+                Some(charcoal) => charcoal.clone(),
+                None => return,
+            };
+            if let Some(e) = charcoal.remove_player(guild_id, true).await.leave_error {
+                error!("Failed to leave empty channel with error: {}", e);
+            }
+        }
+    }
+}
+
+/// Pass a voice state update to Charcoal.
+/// Returns the amount of listeners left in the bot's channel if the update was for another user in a guild with a player
+pub async fn handle_voice_state_update(ctx: &Context, state: &VoiceState) -> Option<usize> {
+    let guild_id = state.guild_id?;
+    let charcoal = ctx.data.read().await.get::<CharcoalKey>()?.clone();
+
+    if state.user_id == ctx.cache.current_user_id() {
+        let listeners = match (state.channel_id, ctx.cache.guild(guild_id)) {
+            (Some(channel_id), Some(guild)) => guild
+                .voice_states
+                .values()
+                .filter(|vs| vs.channel_id == Some(channel_id) && vs.user_id != state.user_id)
+                .filter(|vs| !is_bot(ctx, vs))
+                .map(|vs| UserId::from(vs.user_id))
+                .collect(),
+            _ => vec![],
+        };
+        charcoal
+            .bot_voice_state_changed(
+                GuildId::from(guild_id),
+                state.channel_id.map(Into::into),
+                listeners,
+            )
+            .await;
+        return None;
+    }
+
+    if is_bot(ctx, state) {
+        return None;
+    }
+
+    charcoal
+        .user_voice_state_changed(
+            GuildId::from(guild_id),
+            UserId::from(state.user_id),
+            state.channel_id.map(Into::into),
+        )
+        .await
+}
+
+/// Whether a voice state belongs to a bot.
+/// Cached voice states usually come without a member, so the user is looked up in the cache instead
+fn is_bot(ctx: &Context, state: &VoiceState) -> bool {
+    match &state.member {
+        Some(member) => member.user.bot,
+        None => ctx.cache.user(state.user_id).is_some_and(|user| user.bot),
+    }
+}
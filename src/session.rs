@@ -0,0 +1,71 @@
+//! Export and restore the state of a PlayerObject, for example to pick up existing Hearth jobs after a restart
+
+use crate::actions::history::HistoryEntry;
+use crate::actions::player::TrackSource;
+use crate::actions::queue::LoopMode;
+use crate::background::processor::{IPCData, JobRoute};
+use crate::ids::{GuildId, JobId, WorkerId};
+use crate::PlayerObject;
+use log::error;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// Serializable snapshot of a PlayerObject
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SessionState {
+    pub guild_id: GuildId,
+    pub job_id: Option<JobId>,
+    pub worker_id: Option<WorkerId>,
+    pub now_playing: Option<TrackSource>,
+    /// Estimated position in the track that is playing
+    pub position: Duration,
+    pub loop_mode: LoopMode,
+    pub queue: Vec<TrackSource>,
+    pub history: Vec<HistoryEntry>,
+}
+
+impl PlayerObject {
+    /// Export the state of this PlayerObject
+    pub async fn export_session(&self) -> SessionState {
+        let playback = self.playback.read().await;
+        SessionState {
+            guild_id: self.guild_id.clone(),
+            job_id: self.job_id.read().await.clone(),
+            worker_id: self.worker_id.read().await.clone(),
+            now_playing: playback.now_playing.clone(),
+            position: playback.position(),
+            loop_mode: playback.loop_mode,
+            queue: self.queue.read().await.iter().cloned().collect(),
+            history: playback.history.iter().cloned().collect(),
+        }
+    }
+    /// Restore a previously exported state onto this PlayerObject without sending anything to the Hearth server.
+    /// The restored job is registered with the background thread so Hearth's messages about it reach this player.
+    /// The guild ID of the state is ignored
+    pub async fn restore_session(&self, state: SessionState) {
+        if let (Some(job_id), Some(worker_id)) = (&state.job_id, &state.worker_id) {
+            let route = JobRoute {
+                guild_id: self.guild_id.clone(),
+                job_id: job_id.clone(),
+                worker_id: worker_id.clone(),
+                response_tx: self.tx.clone(),
+            };
+            if let Err(e) = self.bg_com_tx.send(IPCData::RegisterJob(route)) {
+                error!("Failed to register restored job with error: {}", e);
+            }
+        }
+        *self.job_id.write().await = state.job_id;
+        *self.worker_id.write().await = state.worker_id;
+        *self.queue.write().await = state.queue.into();
+        let resume_queue = state.now_playing.is_some();
+        self.playback.write().await.restore(
+            state.now_playing,
+            state.position,
+            state.loop_mode,
+            state.history,
+        );
+        if resume_queue {
+            self.start_queue_runner().await;
+        }
+    }
+}
@@ -0,0 +1,171 @@
+//! Conveniences for using Charcoal with twilight.
+//!
+//! Twilight has no type-map, so keep a [`TwilightCharcoal`] in your bot's shared state
+//! and pass gateway events to [`TwilightCharcoal::process`] to keep players in sync with voice states
+
+use crate::ids::{GuildId, RoleId, UserId, VoiceChannelId};
+use crate::{init_charcoal, Charcoal, CharcoalConfig, InitError, PlayerHandle};
+use dashmap::DashMap;
+use log::error;
+use std::collections::HashMap;
+use std::ops::Deref;
+use std::sync::Arc;
+use twilight_model::gateway::event::Event;
+use twilight_model::id::marker::{ChannelMarker, GuildMarker, RoleMarker, UserMarker};
+use twilight_model::id::Id;
+use twilight_model::voice::VoiceState;
+
+impl From<Id<GuildMarker>> for GuildId {
+    fn from(id: Id<GuildMarker>) -> Self {
+        id.get().into()
+    }
+}
+
+impl From<Id<ChannelMarker>> for VoiceChannelId {
+    fn from(id: Id<ChannelMarker>) -> Self {
+        id.get().into()
+    }
+}
+
+impl From<Id<UserMarker>> for UserId {
+    fn from(id: Id<UserMarker>) -> Self {
+        id.get().into()
+    }
+}
+
+impl From<Id<RoleMarker>> for RoleId {
+    fn from(id: Id<RoleMarker>) -> Self {
+        id.get().into()
+    }
+}
+
+/// Charcoal handle for twilight bots. It is cheap to clone and derefs to [`Charcoal`].
+///
+/// Twilight does not cache voice states by default so the voice states of every guild are kept here
+#[derive(Clone)]
+pub struct TwilightCharcoal {
+    charcoal: Charcoal,
+    current_user_id: Id<UserMarker>,
+    /// Channel of every human user and the bot in a voice channel, per guild
+    voice_states: Arc<DashMap<GuildId, HashMap<UserId, VoiceChannelId>>>,
+    leave_when_alone: bool,
+}
+
+impl TwilightCharcoal {
+    /// `current_user_id` is the ID of the bot user, found in the `Ready` event
+    pub fn new(charcoal: Charcoal, current_user_id: Id<UserMarker>) -> Self {
+        TwilightCharcoal {
+            charcoal,
+            current_user_id,
+            voice_states: Arc::new(DashMap::new()),
+            leave_when_alone: false,
+        }
+    }
+    /// Initializes charcoal and wraps it for use with twilight
+    pub async fn init(
+        broker: String,
+        config: CharcoalConfig,
+        current_user_id: Id<UserMarker>,
+    ) -> Result<Self, InitError> {
+        let charcoal = init_charcoal(broker, config).await?;
+        Ok(TwilightCharcoal::new(charcoal, current_user_id))
+    }
+    /// Leave the channel and remove the player once the last listener leaves
+    pub fn leave_when_alone(mut self, leave_when_alone: bool) -> Self {
+        self.leave_when_alone = leave_when_alone;
+        self
+    }
+    pub fn charcoal(&self) -> &Charcoal {
+        &self.charcoal
+    }
+    /// Get the voice channel a user is in, as last seen on the gateway
+    pub fn user_voice_channel(
+        &self,
+        guild_id: Id<GuildMarker>,
+        user_id: Id<UserMarker>,
+    ) -> Option<VoiceChannelId> {
+        self.voice_states
+            .get(&GuildId::from(guild_id))?
+            .get(&UserId::from(user_id))
+            .cloned()
+    }
+    /// Keep track of voice states. Pass every gateway event to this
+    pub async fn process(&self, event: &Event) {
+        match event {
+            Event::GuildCreate(guild) => {
+                let states = guild
+                    .voice_states
+                    .iter()
+                    .filter(|vs| !is_other_bot(vs, self.current_user_id))
+                    .filter_map(|vs| Some((vs.user_id.into(), vs.channel_id?.into())))
+                    .collect();
+                self.voice_states.insert(guild.id.into(), states);
+            }
+            Event::GuildDelete(guild) => {
+                self.voice_states.remove(&GuildId::from(guild.id));
+            }
+            Event::VoiceStateUpdate(update) => self.handle_voice_state_update(&update.0).await,
+            _ => {}
+        }
+    }
+    /// Pass a single voice state update to Charcoal
+    pub async fn handle_voice_state_update(&self, state: &VoiceState) {
+        let guild_id = match state.guild_id {
+            Some(guild_id) => GuildId::from(guild_id),
+            None => return,
+        };
+        if is_other_bot(state, self.current_user_id) {
+            return;
+        }
+
+        let user_id = UserId::from(state.user_id);
+        let channel_id = state.channel_id.map(VoiceChannelId::from);
+        let listeners: Vec<UserId> = {
+            let mut states = self.voice_states.entry(guild_id.clone()).or_default();
+            match &channel_id {
+                Some(channel_id) => states.insert(user_id.clone(), channel_id.clone()),
+                None => states.remove(&user_id),
+            };
+            states
+                .iter()
+                .filter(|(user, channel)| {
+                    Some(*channel) == channel_id.as_ref() && **user != user_id
+                })
+                .map(|(user, _)| user.clone())
+                .collect()
+        };
+
+        if state.user_id == self.current_user_id {
+            self.charcoal
+                .bot_voice_state_changed(guild_id, channel_id, listeners)
+                .await;
+            return;
+        }
+
+        let remaining = self
+            .charcoal
+            .user_voice_state_changed(guild_id.clone(), user_id, channel_id)
+            .await;
+        if self.leave_when_alone && remaining == Some(0) {
+            if let Some(e) = self.charcoal.remove_player(guild_id, true).await.leave_error {
+                error!("Failed to leave empty channel with error: {}", e);
+            }
+        }
+    }
+    /// Get the PlayerObject of a guild
+    pub fn player_for(&self, guild_id: Id<GuildMarker>) -> Option<PlayerHandle> {
+        self.charcoal.player(guild_id)
+    }
+}
+
+impl Deref for TwilightCharcoal {
+    type Target = Charcoal;
+
+    fn deref(&self) -> &Self::Target {
+        &self.charcoal
+    }
+}
+
+fn is_other_bot(state: &VoiceState, current_user_id: Id<UserMarker>) -> bool {
+    state.user_id != current_user_id && state.member.as_ref().is_some_and(|m| m.user.bot)
+}
@@ -0,0 +1,68 @@
+//! Keeps players in sync with Discord voice states.
+//! Discord library integrations (see the `serenity` and `twilight` modules) feed voice state updates into these functions
+
+use crate::ids::{GuildId, UserId, VoiceChannelId};
+use crate::Charcoal;
+use log::{error, info};
+
+impl Charcoal {
+    /// Call when the bot's own voice state changes.
+    /// `listeners` are the users other than the bot that are in `channel_id`.
+    /// If the bot was disconnected the player leaves its Hearth job and is removed
+    pub async fn bot_voice_state_changed(
+        &self,
+        guild_id: GuildId,
+        channel_id: Option<VoiceChannelId>,
+        listeners: Vec<UserId>,
+    ) {
+        let player = match self.player(&guild_id) {
+            Some(player) => player,
+            None => return,
+        };
+
+        match channel_id {
+            Some(channel_id) => {
+                let mut tracked = player.voice_channel_id.write().await;
+                if tracked.as_ref() != Some(&channel_id) {
+                    info!(
+                        "Bot was moved to channel {} in guild {}",
+                        channel_id, guild_id
+                    );
+                }
+                *tracked = Some(channel_id);
+                *player.listeners.write().await = listeners.into_iter().collect();
+            }
+            None => {
+                if player.voice_channel_id.read().await.is_none() {
+                    return;
+                }
+                info!(
+                    "Bot was disconnected in guild {}, cleaning up player",
+                    guild_id
+                );
+                if let Some(e) = self.remove_player(&guild_id, true).await.leave_error {
+                    error!("Failed to clean up disconnected player with error: {}", e);
+                }
+            }
+        }
+    }
+    /// Call when the voice state of a user other than the bot changes.
+    /// Returns the amount of listeners left in the player's channel, or None if there is no player for the guild
+    pub async fn user_voice_state_changed(
+        &self,
+        guild_id: GuildId,
+        user_id: UserId,
+        channel_id: Option<VoiceChannelId>,
+    ) -> Option<usize> {
+        let player = self.player(&guild_id)?;
+        let tracked = player.voice_channel_id.read().await.clone()?;
+
+        let mut listeners = player.listeners.write().await;
+        if channel_id.as_ref() == Some(&tracked) {
+            listeners.insert(user_id);
+        } else {
+            listeners.remove(&user_id);
+        }
+        Some(listeners.len())
+    }
+}
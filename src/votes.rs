@@ -0,0 +1,327 @@
+//! Votes among the listeners of a player, for actions such as skipping a track.
+//!
+//! Each player has at most one running vote per action. A vote expires after its timeout
+//! or once the current track changes. Listeners are only known if voice state updates are passed to Charcoal,
+//! see the `voice_state` module. Without them anyone can vote
+
+use crate::actions::history::HistoryManager;
+use crate::actions::player::PlayerActionError;
+use crate::actions::queue::QueueManager;
+use crate::actions::track_manager::{TrackActionError, TrackManager};
+use crate::ids::UserId;
+use crate::permissions::PlayerAction;
+use crate::PlayerObject;
+use snafu::prelude::*;
+use std::collections::HashSet;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Snafu)]
+pub enum VoteError {
+    #[snafu(display("You must be listening to vote"))]
+    NotListening,
+    #[snafu(display("Can't vote to {action}"))]
+    UnsupportedAction { action: PlayerAction },
+    #[snafu(display("Failed to {action} after the vote passed"))]
+    FailedToRunPlayerAction {
+        action: PlayerAction,
+        #[snafu(source(from(PlayerActionError, Box::new)))]
+        source: Box<PlayerActionError>,
+    },
+    #[snafu(display("Failed to {action} after the vote passed"))]
+    FailedToRunTrackAction {
+        action: PlayerAction,
+        source: TrackActionError,
+    },
+}
+
+/// How many votes are needed for a vote to pass
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum VoteThreshold {
+    /// Fraction of the listeners in the player's channel, rounded up
+    Fraction(f64),
+    /// Fixed amount of votes
+    Count(usize),
+}
+
+#[derive(Clone, Debug)]
+pub struct VoteConfig {
+    pub threshold: VoteThreshold,
+    /// How long a vote stays open after the first vote
+    pub timeout: Duration,
+}
+
+impl Default for VoteConfig {
+    fn default() -> Self {
+        VoteConfig {
+            threshold: VoteThreshold::Fraction(0.5),
+            timeout: Duration::from_secs(60),
+        }
+    }
+}
+
+/// Result of casting a vote
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VoteOutcome {
+    /// The threshold was reached and the vote was closed
+    Passed { votes: usize, required: usize },
+    /// More votes are needed
+    Pending { votes: usize, required: usize },
+    /// The user has already voted
+    AlreadyVoted { votes: usize, required: usize },
+}
+
+/// A vote that is currently open on a player
+pub(crate) struct Vote {
+    voters: HashSet<UserId>,
+    started_at: Instant,
+    /// How long the vote stays open, taken from the `VoteConfig` it was started with
+    timeout: Duration,
+    /// Track number of the player when the vote started
+    track_number: u64,
+}
+
+impl Vote {
+    /// Whether the vote still counts. Votes close after their timeout or once the track changed
+    fn is_open(&self, track_number: u64) -> bool {
+        self.track_number == track_number && self.started_at.elapsed() < self.timeout
+    }
+}
+
+/// Votes needed to pass with the given amount of listeners. At least one vote is always needed
+fn required_votes(threshold: VoteThreshold, listeners: usize) -> usize {
+    match threshold {
+        VoteThreshold::Fraction(fraction) => (listeners as f64 * fraction).ceil() as usize,
+        VoteThreshold::Count(count) => count,
+    }
+    .max(1)
+}
+
+impl PlayerObject {
+    /// Cast a vote for an action. The vote is closed once the threshold is reached, but the action is not run.
+    /// Use [`PlayerObject::vote_and_run`] to also run the action
+    pub async fn vote(
+        &self,
+        user_id: UserId,
+        action: PlayerAction,
+        config: &VoteConfig,
+    ) -> Result<VoteOutcome, VoteError> {
+        let listeners = self.listeners().await;
+        ensure!(
+            listeners.is_empty() || listeners.contains(&user_id),
+            NotListeningSnafu
+        );
+        let required = required_votes(config.threshold, listeners.len());
+        let track_number = self.playback.read().await.track_number;
+
+        let mut votes = self.votes.lock().await;
+        votes.retain(|_, vote| vote.is_open(track_number));
+        let vote = votes.entry(action).or_insert_with(|| Vote {
+            voters: HashSet::new(),
+            started_at: Instant::now(),
+            timeout: config.timeout,
+            track_number,
+        });
+
+        let added = vote.voters.insert(user_id);
+        let count = vote.voters.len();
+        if count >= required {
+            votes.remove(&action);
+            return Ok(VoteOutcome::Passed {
+                votes: count,
+                required,
+            });
+        }
+        Ok(match added {
+            true => VoteOutcome::Pending {
+                votes: count,
+                required,
+            },
+            false => VoteOutcome::AlreadyVoted {
+                votes: count,
+                required,
+            },
+        })
+    }
+    /// Cast a vote for an action and run the action once the vote passes.
+    /// Supports skip, previous, stop, clear queue, pause and resume
+    pub async fn vote_and_run(
+        &self,
+        user_id: UserId,
+        action: PlayerAction,
+        config: &VoteConfig,
+    ) -> Result<VoteOutcome, VoteError> {
+        ensure!(
+            matches!(
+                action,
+                PlayerAction::Skip
+                    | PlayerAction::Previous
+                    | PlayerAction::Stop
+                    | PlayerAction::ClearQueue
+                    | PlayerAction::Pause
+                    | PlayerAction::Resume
+            ),
+            UnsupportedActionSnafu { action }
+        );
+
+        let outcome = self.vote(user_id, action, config).await?;
+        if !matches!(outcome, VoteOutcome::Passed { .. }) {
+            return Ok(outcome);
+        }
+
+        match action {
+            PlayerAction::Skip => self
+                .skip()
+                .await
+                .context(FailedToRunPlayerActionSnafu { action })?,
+            PlayerAction::Previous => {
+                self.previous()
+                    .await
+                    .context(FailedToRunPlayerActionSnafu { action })?;
+            }
+            PlayerAction::Stop => {
+                self.clear_queue()
+                    .await
+                    .context(FailedToRunPlayerActionSnafu { action })?;
+                self.skip()
+                    .await
+                    .context(FailedToRunPlayerActionSnafu { action })?
+            }
+            PlayerAction::ClearQueue => self
+                .clear_queue()
+                .await
+                .context(FailedToRunPlayerActionSnafu { action })?,
+            PlayerAction::Pause => self
+                .pause_playback()
+                .await
+                .context(FailedToRunTrackActionSnafu { action })?,
+            PlayerAction::Resume => self
+                .resume_playback()
+                .await
+                .context(FailedToRunTrackActionSnafu { action })?,
+            _ => unreachable!("unsupported actions are rejected before voting"),
+        }
+        Ok(outcome)
+    }
+    /// Amount of votes cast for an action, or None if no vote is open
+    pub async fn vote_count(&self, action: PlayerAction) -> Option<usize> {
+        let track_number = self.playback.read().await.track_number;
+        let mut votes = self.votes.lock().await;
+        votes.retain(|_, vote| vote.is_open(track_number));
+        votes.get(&action).map(|v| v.voters.len())
+    }
+    /// Close the vote for an action without running it
+    pub async fn cancel_vote(&self, action: PlayerAction) {
+        self.votes.lock().await.remove(&action);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::capabilities::Capabilities;
+    use crate::ids::GuildId;
+    use std::sync::Arc;
+    use tokio::sync::broadcast;
+
+    fn player() -> PlayerObject {
+        let (bg_tx, _) = broadcast::channel(16);
+        PlayerObject::new(
+            GuildId::from_raw("1"),
+            bg_tx,
+            Arc::new(std::sync::RwLock::new(None)),
+            Arc::new(Capabilities::default()),
+        )
+    }
+
+    fn count(count: usize) -> VoteConfig {
+        VoteConfig {
+            threshold: VoteThreshold::Count(count),
+            ..VoteConfig::default()
+        }
+    }
+
+    #[test]
+    fn thresholds() {
+        assert_eq!(required_votes(VoteThreshold::Fraction(0.5), 3), 2);
+        assert_eq!(required_votes(VoteThreshold::Fraction(0.5), 4), 2);
+        assert_eq!(required_votes(VoteThreshold::Fraction(1.0), 4), 4);
+        assert_eq!(required_votes(VoteThreshold::Fraction(0.5), 0), 1);
+        assert_eq!(required_votes(VoteThreshold::Count(3), 10), 3);
+        assert_eq!(required_votes(VoteThreshold::Count(0), 10), 1);
+    }
+
+    #[tokio::test]
+    async fn votes_pass_at_threshold() {
+        let player = player();
+        let config = count(2);
+        let (a, b) = (UserId::from_raw("1"), UserId::from_raw("2"));
+        let skip = PlayerAction::Skip;
+
+        let pending = player.vote(a.clone(), skip, &config).await.unwrap();
+        assert_eq!(
+            pending,
+            VoteOutcome::Pending {
+                votes: 1,
+                required: 2
+            }
+        );
+        let again = player.vote(a, skip, &config).await.unwrap();
+        assert_eq!(
+            again,
+            VoteOutcome::AlreadyVoted {
+                votes: 1,
+                required: 2
+            }
+        );
+        assert_eq!(player.vote_count(skip).await, Some(1));
+
+        let passed = player.vote(b, skip, &config).await.unwrap();
+        assert_eq!(
+            passed,
+            VoteOutcome::Passed {
+                votes: 2,
+                required: 2
+            }
+        );
+        assert_eq!(player.vote_count(skip).await, None);
+    }
+
+    #[tokio::test]
+    async fn votes_expire() {
+        let player = player();
+        let config = VoteConfig {
+            timeout: Duration::ZERO,
+            ..count(2)
+        };
+        player
+            .vote(UserId::from_raw("1"), PlayerAction::Skip, &config)
+            .await
+            .unwrap();
+        assert_eq!(player.vote_count(PlayerAction::Skip).await, None);
+    }
+
+    #[tokio::test]
+    async fn votes_reset_when_track_changes() {
+        let player = player();
+        let config = count(2);
+        player
+            .vote(UserId::from_raw("1"), PlayerAction::Skip, &config)
+            .await
+            .unwrap();
+        assert_eq!(player.vote_count(PlayerAction::Skip).await, Some(1));
+
+        player.playback.write().await.stop();
+        assert_eq!(player.vote_count(PlayerAction::Skip).await, None);
+        let outcome = player
+            .vote(UserId::from_raw("1"), PlayerAction::Skip, &config)
+            .await
+            .unwrap();
+        assert_eq!(
+            outcome,
+            VoteOutcome::Pending {
+                votes: 1,
+                required: 2
+            }
+        );
+    }
+}